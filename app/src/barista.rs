@@ -1,4 +1,6 @@
-use anyhow::Result;
+use std::iter;
+
+use anyhow::{anyhow, Result};
 use log::*;
 use r2d2::{self, Pool};
 use serde::{Deserialize, Serialize};
@@ -9,15 +11,33 @@ use infra::{
     persistence::{Storage, StoragePending},
 };
 
-use crate::menu::Drink;
-use crate::orders::FulfillDrink;
-use crate::orders::Order;
+use crate::menu::{Drink, Modifier};
+use crate::orders::{Compensation, DrinkCancelled, DrinkFailed, DrinkStarted, FulfillDrink, Order};
 use crate::services::{Commandable, Request};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PrepareDrink {
     pub drink_id: Id<Drink>,
     pub order_id: Id<Order>,
+    pub line: usize,
+    pub quantity: u32,
+    pub modifiers: Vec<Modifier>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CancelDrink {
+    pub drink_id: Id<Drink>,
+    pub order_id: Id<Order>,
+    pub line: usize,
+}
+
+/// Reports that a drink could not be made, eg: because we've run out of
+/// something.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FailDrink {
+    pub drink_id: Id<Drink>,
+    pub order_id: Id<Order>,
+    pub line: usize,
 }
 
 #[derive(Debug)]
@@ -37,39 +57,96 @@ pub struct DrinkPreparation {
     #[serde(flatten)]
     pub(super) mbox: MailBox<PreparationMsg>,
     pub(super) drink_id: Id<Drink>,
+    #[serde(default = "one")]
+    pub(super) quantity: u32,
+    #[serde(default)]
+    pub(super) modifiers: Vec<Modifier>,
+    #[serde(default)]
+    pub(super) compensation: Option<Compensation>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub(super) enum PreparationMsg {
+    // Sent for single drink orders.
     FulfillDrink(Id<Order>),
+    Started {
+        order_id: Id<Order>,
+        line: usize,
+    },
+    FulfillLine {
+        order_id: Id<Order>,
+        line: usize,
+    },
+    Cancelled {
+        order_id: Id<Order>,
+        line: usize,
+        compensation: Compensation,
+    },
+    Failed {
+        order_id: Id<Order>,
+        line: usize,
+    },
 }
 
-impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + StoragePending + Send + 'static>
-    Barista<M>
-{
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Barista<M> {
     pub fn new(db: Pool<M>) -> Result<Self> {
         Ok(Barista { db })
     }
+
+    /// Loads the preparation for the line, wherever it was created.
+    fn load(
+        &self,
+        docs: &D,
+        order_id: Id<Order>,
+        drink_id: Id<Drink>,
+        line: usize,
+    ) -> Result<Option<DrinkPreparation>> {
+        let current = DrinkPreparation::id_for(order_id, drink_id, line);
+        for prep_id in
+            iter::once(current).chain(DrinkPreparation::legacy_ids(order_id, drink_id, line))
+        {
+            if let Some(prep) = docs.load(&prep_id)? {
+                return Ok(Some(prep));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl<
         M: r2d2::ManageConnection<Connection = D>,
         D: Storage + StoragePending + Send + 'static,
-        O: Commandable<FulfillDrink>,
+        O: Commandable<DrinkStarted>
+            + Commandable<FulfillDrink>
+            + Commandable<DrinkCancelled>
+            + Commandable<DrinkFailed>,
+    > BaristaWorker<M, O>
+{
+    pub fn process_action(&self) -> Result<()> {
+        self.db
+            .get()?
+            .subscribe(|doc: &mut DrinkPreparation| self.handle(doc))?;
+        Ok(())
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection,
+        O: Commandable<DrinkStarted>
+            + Commandable<FulfillDrink>
+            + Commandable<DrinkCancelled>
+            + Commandable<DrinkFailed>,
     > BaristaWorker<M, O>
 {
     pub fn new(db: Pool<M>, orders: O) -> Result<Self> {
         Ok(BaristaWorker { db, orders })
     }
 
-    pub fn process_action(&self) -> Result<()> {
-        self.db.get()?.subscribe(|doc: &mut DrinkPreparation| {
-            info!("Found pending document: {:?}", doc);
-            while let Some(act) = doc.mbox.take_one() {
-                self.handle_barista_action(act)?;
-            }
-            Ok(())
-        })?;
+    pub fn handle(&self, doc: &mut DrinkPreparation) -> Result<()> {
+        info!("Found pending document: {:?}", doc);
+        while let Some(act) = doc.mbox.take_one() {
+            self.handle_barista_action(act)?;
+        }
         Ok(())
     }
 
@@ -78,7 +155,34 @@ impl<
         match action {
             PreparationMsg::FulfillDrink(order_id) => {
                 info!("Fulfil drink: order:{}", order_id);
-                self.orders.execute(FulfillDrink { order_id })?
+                self.orders.execute(FulfillDrink { order_id, line: 0 })?
+            }
+            PreparationMsg::Started { order_id, line } => {
+                info!("Drink started: order:{}; line:{}", order_id, line);
+                self.orders.execute(DrinkStarted { order_id, line })?
+            }
+            PreparationMsg::FulfillLine { order_id, line } => {
+                info!("Fulfil drink: order:{}; line:{}", order_id, line);
+                self.orders.execute(FulfillDrink { order_id, line })?
+            }
+            PreparationMsg::Cancelled {
+                order_id,
+                line,
+                compensation,
+            } => {
+                info!(
+                    "Drink cancelled: order:{}; line:{}; {:?}",
+                    order_id, line, compensation
+                );
+                self.orders.execute(DrinkCancelled {
+                    order_id,
+                    line,
+                    compensation,
+                })?
+            }
+            PreparationMsg::Failed { order_id, line } => {
+                info!("Drink failed: order:{}; line:{}", order_id, line);
+                self.orders.execute(DrinkFailed { order_id, line })?
             }
         };
         Ok(())
@@ -89,17 +193,83 @@ impl Request for PrepareDrink {
     type Resp = ();
 }
 
+impl Request for CancelDrink {
+    type Resp = ();
+}
+
+impl Request for FailDrink {
+    type Resp = ();
+}
+
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
     Commandable<PrepareDrink> for Barista<M>
 {
     fn execute(&self, order: PrepareDrink) -> Result<()> {
-        let PrepareDrink { drink_id, order_id } = order;
-        info!("Preparing drink {}!", drink_id);
+        let PrepareDrink {
+            drink_id,
+            order_id,
+            line,
+            quantity,
+            modifiers,
+        } = order;
+        info!(
+            "Preparing {} of drink {} with {:?}!",
+            quantity, drink_id, modifiers
+        );
+
+        let conn = self.db.get()?;
+        let prep_id = DrinkPreparation::id_for(order_id, drink_id, line);
+
+        let mut prep = self
+            .load(&conn, order_id, drink_id, line)?
+            .unwrap_or_else(|| {
+                let mbox = MailBox::empty();
+                let meta = DocMeta::new_with_id(prep_id);
+
+                DrinkPreparation {
+                    meta,
+                    mbox,
+                    drink_id,
+                    quantity,
+                    modifiers,
+                    compensation: None,
+                }
+            });
+
+        if prep.compensation.is_some() {
+            info!("Preparation {} was cancelled", prep.meta.id);
+            return Ok(());
+        }
+
+        prep.mbox.send(PreparationMsg::Started { order_id, line });
+        prep.mbox
+            .send(PreparationMsg::FulfillLine { order_id, line });
+
+        conn.save(&mut prep)?;
+        debug!("Saved {:?}", prep);
+
+        Ok(())
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<CancelDrink> for Barista<M>
+{
+    fn execute(&self, cancel: CancelDrink) -> Result<()> {
+        let CancelDrink {
+            drink_id,
+            order_id,
+            line,
+        } = cancel;
 
         let conn = self.db.get()?;
-        let prep_id = order_id.untyped().typed::<DrinkPreparation>();
+        let prep_id = DrinkPreparation::id_for(order_id, drink_id, line);
 
-        let mut prep = self.db.load(&prep_id)?.unwrap_or_else(|| {
+        let existing = self.load(&conn, order_id, drink_id, line)?;
+        let started = existing.is_some();
+        // If the request to prepare the drink arrives after this, we'll find
+        // the preparation already stopped.
+        let mut prep = existing.unwrap_or_else(|| {
             let mbox = MailBox::empty();
             let meta = DocMeta::new_with_id(prep_id);
 
@@ -107,10 +277,66 @@ impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
                 meta,
                 mbox,
                 drink_id,
+                quantity: 0,
+                modifiers: Vec::new(),
+                compensation: None,
             }
         });
 
-        prep.mbox.send(PreparationMsg::FulfillDrink(order_id));
+        let compensation = match prep.compensation {
+            Some(compensation) => compensation,
+            None if !started => Compensation::Stopped,
+            None => {
+                let fulfil = PreparationMsg::FulfillLine { order_id, line };
+                let mut started = true;
+                prep.mbox.retain(|m| {
+                    let pending = *m == fulfil || *m == PreparationMsg::FulfillDrink(order_id);
+                    started &= !pending;
+                    !pending
+                });
+                if started {
+                    Compensation::Wasted
+                } else {
+                    Compensation::Stopped
+                }
+            }
+        };
+        info!("Cancelling {}: {:?}", prep.meta.id, compensation);
+
+        prep.compensation = Some(compensation);
+        prep.mbox.send(PreparationMsg::Cancelled {
+            order_id,
+            line,
+            compensation,
+        });
+
+        conn.save(&mut prep)?;
+        debug!("Saved {:?}", prep);
+
+        Ok(())
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Commandable<FailDrink>
+    for Barista<M>
+{
+    fn execute(&self, fail: FailDrink) -> Result<()> {
+        let FailDrink {
+            drink_id,
+            order_id,
+            line,
+        } = fail;
+
+        let conn = self.db.get()?;
+        let prep_id = DrinkPreparation::id_for(order_id, drink_id, line);
+        let mut prep = self
+            .load(&conn, order_id, drink_id, line)?
+            .ok_or_else(|| anyhow!("Preparation not found? id:{}", prep_id))?;
+
+        let fulfil = PreparationMsg::FulfillLine { order_id, line };
+        prep.mbox
+            .retain(|m| *m != fulfil && *m != PreparationMsg::FulfillDrink(order_id));
+        prep.mbox.send(PreparationMsg::Failed { order_id, line });
 
         conn.save(&mut prep)?;
         debug!("Saved {:?}", prep);
@@ -119,6 +345,27 @@ impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
     }
 }
 
+impl DrinkPreparation {
+    /// Each line of an order has its own preparation.
+    pub(crate) fn id_for(order_id: Id<Order>, drink_id: Id<Drink>, line: usize) -> Id<Self> {
+        order_id.derive((drink_id, line))
+    }
+
+    /// Where preparations were created before each line had its own, newest
+    /// first. Orders only had a single drink then, so only the first line
+    /// may have one.
+    fn legacy_ids(order_id: Id<Order>, drink_id: Id<Drink>, line: usize) -> Vec<Id<Self>> {
+        if line != 0 {
+            return Vec::new();
+        }
+        vec![order_id.derive(drink_id), order_id.untyped().typed()]
+    }
+}
+
+fn one() -> u32 {
+    1
+}
+
 impl Entity for DrinkPreparation {
     const PREFIX: &'static str = "drink-preparation";
 }
@@ -131,3 +378,64 @@ impl HasMeta for DrinkPreparation {
         &mut self.meta
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MemStore;
+    use infra::ids::IdGen;
+
+    fn preparation(prep_id: Id<DrinkPreparation>, drink_id: Id<Drink>) -> DrinkPreparation {
+        DrinkPreparation {
+            meta: DocMeta::new_with_id(prep_id),
+            mbox: MailBox::empty(),
+            drink_id,
+            quantity: 1,
+            modifiers: Vec::new(),
+            compensation: None,
+        }
+    }
+
+    #[test]
+    fn should_carry_on_with_preparations_under_legacy_ids() -> Result<()> {
+        let idgen = IdGen::new();
+        let drink_id = idgen.generate();
+        let line = 0;
+        for legacy in 0..2 {
+            let store = MemStore::default();
+            let barista = Barista::new(store.pool())?;
+            let order_id = idgen.generate();
+            let prep_id = DrinkPreparation::legacy_ids(order_id, drink_id, line)[legacy];
+            store.save(&mut preparation(prep_id, drink_id))?;
+
+            // Redelivered after we changed how preparations are named.
+            barista.execute(PrepareDrink {
+                drink_id,
+                order_id,
+                line,
+                quantity: 1,
+                modifiers: Vec::new(),
+            })?;
+            barista.execute(CancelDrink {
+                drink_id,
+                order_id,
+                line,
+            })?;
+
+            let current = DrinkPreparation::id_for(order_id, drink_id, line);
+            assert!(store.load::<DrinkPreparation>(&current)?.is_none());
+            let prep: DrinkPreparation = store.load(&prep_id)?.expect("preparation");
+            assert_eq!(prep.compensation, Some(Compensation::Stopped));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_first_line_should_have_legacy_ids() {
+        let idgen = IdGen::new();
+        let (order_id, drink_id) = (idgen.generate(), idgen.generate());
+
+        assert_eq!(DrinkPreparation::legacy_ids(order_id, drink_id, 0).len(), 2);
+        assert_eq!(DrinkPreparation::legacy_ids(order_id, drink_id, 1), vec![]);
+    }
+}
@@ -1,7 +1,12 @@
-use anyhow::Result;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{Error, Result};
 use chrono::{DateTime, SecondsFormat, Utc};
-use infra::ids::IdGen;
-use infra::untyped_ids::UntypedId;
+use infra::ids::{AnyId, IdGen};
+use infra::untyped_ids::{IdKey, UntypedId};
+use serde::Serialize;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -13,22 +18,78 @@ enum Commands {
     Hash(Hash),
     #[structopt(name = "decompose", about = "Decompose Identifiers")]
     Decompose(Decompose),
+    #[structopt(name = "at", about = "Generate an Identifier for a given time")]
+    At(At),
+}
+
+#[derive(Debug, StructOpt)]
+struct Output {
+    /// Print each identifier as a JSON object, one per line
+    #[structopt(long = "json")]
+    json: bool,
+    /// Append a check symbol, to catch mistyped identifiers
+    #[structopt(long = "check")]
+    check: bool,
 }
 
 #[derive(Debug, StructOpt)]
 struct Generate {
     #[structopt(short = "n", long = "count", default_value = "1")]
     count: usize,
+    /// Entity prefix, eg: "order"
+    #[structopt(short = "p", long = "prefix")]
+    prefix: Option<String>,
+    #[structopt(flatten)]
+    output: Output,
 }
 
 #[derive(Debug, StructOpt)]
 struct Hash {
+    /// Entity prefix, eg: "drink"; gives the same ids as the application
+    #[structopt(short = "p", long = "prefix")]
+    prefix: Option<String>,
+    /// Deployment secret that hashed ids are keyed with
+    #[structopt(long = "secret")]
+    secret: Option<String>,
     inputs: Vec<String>,
+    #[structopt(flatten)]
+    output: Output,
 }
 
 #[derive(Debug, StructOpt)]
 struct Decompose {
-    ids: Vec<UntypedId>,
+    ids: Vec<CliId>,
+    #[structopt(flatten)]
+    output: Output,
+}
+
+#[derive(Debug, StructOpt)]
+struct At {
+    /// RFC 3339 timestamp, eg: 2020-05-01T12:00:00Z
+    timestamp: DateTime<Utc>,
+    /// Entity prefix, eg: "order"
+    #[structopt(short = "p", long = "prefix")]
+    prefix: Option<String>,
+    /// Use the greatest id at this time, rather than the least
+    #[structopt(long = "upper")]
+    upper: bool,
+    #[structopt(flatten)]
+    output: Output,
+}
+
+/// An identifier, with or without an entity prefix.
+#[derive(Debug)]
+struct CliId {
+    prefix: Option<String>,
+    id: UntypedId,
+}
+
+#[derive(Debug, Serialize)]
+struct IdInfo {
+    id: String,
+    prefix: Option<String>,
+    timestamp: String,
+    random: String,
 }
 
 fn main() -> Result<()> {
@@ -38,28 +99,102 @@ fn main() -> Result<()> {
         Commands::Generate(opt) => {
             let idgen = IdGen::new();
             for _ in 0..opt.count {
-                println!("{}", idgen.untyped());
+                let id = CliId::new(opt.prefix.clone(), idgen.untyped());
+                id.print(&opt.output)?;
             }
         }
         Commands::Hash(opt) => {
+            let key = opt
+                .secret
+                .as_ref()
+                .map(|s| IdKey::from_secret(s))
+                .unwrap_or_default();
             for inp in opt.inputs.iter() {
-                let id = UntypedId::hashed(inp.as_bytes());
-                println!("{}", id);
+                let id = match opt.prefix {
+                    Some(ref prefix) => AnyId::hashed_with(prefix, &key, inp.as_str()).into(),
+                    None => CliId::new(None, UntypedId::hashed_with(&key, inp.as_bytes())),
+                };
+                id.print(&opt.output)?;
             }
         }
 
         Commands::Decompose(opt) => {
             for id in opt.ids {
-                let stamp: DateTime<Utc> = id.timestamp().into();
-                let random = id.random();
-                println!(
-                    "t:{}; r:0x{:0>16x}",
-                    stamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
-                    random
-                );
+                if opt.output.json {
+                    id.print(&opt.output)?;
+                    continue;
+                }
+                let info = id.info(&opt.output);
+                if let Some(prefix) = info.prefix {
+                    print!("p:{}; ", prefix);
+                }
+                println!("t:{}; r:{}", info.timestamp, info.random);
             }
         }
+
+        Commands::At(opt) => {
+            let random = if opt.upper { u64::MAX } else { 0 };
+            let id = UntypedId::at(SystemTime::from(opt.timestamp), random)?;
+            CliId::new(opt.prefix, id).print(&opt.output)?;
+        }
     }
 
     Ok(())
 }
+
+impl CliId {
+    fn new(prefix: Option<String>, id: UntypedId) -> Self {
+        CliId { prefix, id }
+    }
+
+    fn info(&self, output: &Output) -> IdInfo {
+        let stamp: DateTime<Utc> = self.id.timestamp().into();
+        IdInfo {
+            id: if output.check {
+                format!("{:#}", self)
+            } else {
+                self.to_string()
+            },
+            prefix: self.prefix.clone(),
+            timestamp: stamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            random: format!("0x{:0>16x}", self.id.random()),
+        }
+    }
+
+    fn print(&self, output: &Output) -> Result<()> {
+        if output.json {
+            println!("{}", serde_json::to_string(&self.info(output))?);
+        } else if output.check {
+            println!("{:#}", self);
+        } else {
+            println!("{}", self);
+        }
+        Ok(())
+    }
+}
+
+impl From<AnyId> for CliId {
+    fn from(src: AnyId) -> Self {
+        CliId::new(Some(src.prefix), src.id)
+    }
+}
+
+impl FromStr for CliId {
+    type Err = Error;
+    fn from_str(src: &str) -> Result<Self> {
+        if src.contains('.') {
+            Ok(src.parse::<AnyId>()?.into())
+        } else {
+            Ok(CliId::new(None, src.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for CliId {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref prefix) = self.prefix {
+            write!(fmt, "{}.", prefix)?;
+        }
+        fmt::Display::fmt(&self.id, fmt)
+    }
+}
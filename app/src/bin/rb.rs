@@ -1,16 +1,31 @@
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::Deserialize;
 use structopt::StructOpt;
 
-use infra::{documents::HasMeta, ids::Id};
+use infra::{
+    documents::HasMeta,
+    ids::{Id, IdGen},
+};
 use rustbucks::{
-    menu::{Drink, ShowMenu},
-    orders::{Order, PlaceOrder, QueryOrder},
+    barista::FailDrink,
+    customers::{Customer, QueryCustomer, RegisterCustomer},
+    menu::Drink,
+    menu::ShowMenu,
+    money::Money,
+    orders::{
+        CancelOrder, CollectOrder, EscalateOverdue, LineItem, Order, OrderState, PickupNumber,
+        PlaceOrder, QueryOrder, QueryOrders, QueryOverdue, QueryPickup,
+    },
+    outbox::{LogMetrics, QueryOutbox},
     services::{Commandable, Queryable},
+    workers::QueryWorkers,
 };
 
 #[derive(Debug, StructOpt)]
@@ -33,7 +48,27 @@ enum Commands {
     #[structopt(name = "order", about = "Place order")]
     Order(PlaceOrderCmd),
     #[structopt(name = "order-status", about = "Show order status")]
-    OrderStatus(OrderStatus),
+    OrderStatus(OrderStatusCmd),
+    #[structopt(name = "orders", about = "List orders placed in a time range")]
+    Orders(OrdersCmd),
+    #[structopt(name = "collect", about = "Record that an order was collected")]
+    Collect(CollectCmd),
+    #[structopt(name = "cancel", about = "Cancel an order")]
+    Cancel(CancelCmd),
+    #[structopt(
+        name = "fail-drink",
+        about = "Record that a line of an order couldn't be made"
+    )]
+    FailDrink(FailDrinkCmd),
+    #[structopt(
+        name = "overdue",
+        about = "List, or escalate, overdue orders placed within the store's overdue_lookback"
+    )]
+    Overdue(OverdueCmd),
+    #[structopt(name = "register-customer", about = "Register a loyalty customer")]
+    RegisterCustomer(RegisterCustomerCmd),
+    #[structopt(name = "customer", about = "Show a customer and their points")]
+    Customer(CustomerCmd),
 
     #[structopt(name = "process-order", about = "Process outstanding order actions")]
     ActionOrder,
@@ -42,18 +77,129 @@ enum Commands {
         about = "Process outstanding barista actions"
     )]
     ActionBarista,
+    #[structopt(
+        name = "process-payment",
+        about = "Process outstanding payment actions"
+    )]
+    ActionPayment,
+    #[structopt(
+        name = "process-customer",
+        about = "Process outstanding customer actions"
+    )]
+    ActionCustomer,
+    #[structopt(name = "outbox-status", about = "Show outbox backlog")]
+    OutboxStatus(OutboxStatusCmd),
+    #[structopt(name = "workers", about = "List live workers")]
+    Workers,
+    #[structopt(name = "work", about = "Process outstanding actions for all entities")]
+    Work,
+    #[structopt(name = "show", about = "Show the document with any known id")]
+    Show(ShowCmd),
 }
 
 #[derive(Debug, StructOpt)]
 struct PlaceOrderCmd {
-    drink_id: Id<Drink>,
+    /// Drinks to order, each as DRINK-ID[:QUANTITY][+GROUP=OPTION...], eg:
+    /// drink.xxx:2+size=large+milk=oat
+    #[structopt(required = true)]
+    drinks: Vec<DrinkArg>,
+    /// Customer to credit loyalty points to
+    #[structopt(long = "customer")]
+    customer: Option<Id<Customer>>,
+    /// Loyalty points to spend on the order
+    #[structopt(long = "redeem", default_value = "0")]
+    redeem: u64,
 }
 
+#[derive(Debug)]
+struct DrinkArg(LineItem);
+
 #[derive(Debug, StructOpt)]
-struct OrderStatus {
+struct CollectCmd {
+    /// Order that was handed over
     order_id: Id<Order>,
 }
 
+#[derive(Debug, StructOpt)]
+struct CancelCmd {
+    /// Order to cancel
+    order_id: Id<Order>,
+}
+
+#[derive(Debug, StructOpt)]
+struct OrderStatusCmd {
+    /// Order id, or today's pickup number, eg: A042
+    order: OrderKey,
+}
+
+#[derive(Debug)]
+enum OrderKey {
+    Id(Id<Order>),
+    Pickup(PickupNumber),
+}
+
+#[derive(Debug, StructOpt)]
+struct OrdersCmd {
+    /// Orders placed at or after this RFC 3339 time, eg: 2020-05-01T09:00:00Z
+    #[structopt(long = "from")]
+    from: Option<DateTime<Utc>>,
+    /// Orders placed before this RFC 3339 time
+    #[structopt(long = "to")]
+    to: Option<DateTime<Utc>>,
+    /// Only orders in this state, eg: ready
+    #[structopt(long = "state")]
+    state: Option<OrderState>,
+    /// Only orders including this drink
+    #[structopt(long = "drink")]
+    drink: Option<Id<Drink>>,
+    /// Continue from where a previous listing left off
+    #[structopt(long = "after")]
+    after: Option<Id<Order>>,
+    #[structopt(short = "n", long = "limit", default_value = "20")]
+    limit: usize,
+}
+
+#[derive(Debug, StructOpt)]
+struct FailDrinkCmd {
+    order_id: Id<Order>,
+    /// Which line of the order, counting from zero
+    line: usize,
+}
+
+#[derive(Debug, StructOpt)]
+struct OverdueCmd {
+    /// Escalate overdue orders to staff, rather than just listing them
+    #[structopt(long = "escalate")]
+    escalate: bool,
+    /// Escalate at this interval (in seconds) rather than once
+    #[structopt(long = "every", requires = "escalate")]
+    every: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RegisterCustomerCmd {
+    name: String,
+    #[structopt(long = "email")]
+    email: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct CustomerCmd {
+    customer_id: Id<Customer>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ShowCmd {
+    id: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct OutboxStatusCmd {
+    /// Publish metrics at this interval (in seconds) rather than printing once
+    #[structopt(long = "publish-every")]
+    publish_every: Option<u64>,
+}
+
 #[derive(Deserialize, Debug)]
 struct Config {
     #[serde(flatten)]
@@ -70,7 +216,7 @@ fn main() -> Result<()> {
 
     config.env_logger.builder().init();
 
-    let rb = rustbucks::RustBucks::new(&config.rustbucks)?;
+    let rb = rustbucks::RustBucks::new(&config.rustbucks, IdGen::monotonic())?;
 
     match opt.command {
         Commands::Setup => {
@@ -80,18 +226,188 @@ fn main() -> Result<()> {
         Commands::ShowMenu => {
             let list = rb.menu()?.query(ShowMenu)?;
             for drink in list {
-                println!("{}: {}", drink.meta().id, drink.name);
+                println!(
+                    "{:#}: {}; price:{}",
+                    drink.meta().id,
+                    drink.name,
+                    price(drink.price.as_ref())
+                );
+                for group in drink.modifiers {
+                    let options = group
+                        .options
+                        .iter()
+                        .map(|o| match group.prices.get(o) {
+                            Some(p) => format!("{} ({})", o, p),
+                            None => o.clone(),
+                        })
+                        .collect::<Vec<_>>();
+                    print!(
+                        "  {} (up to {}): {}",
+                        group.name,
+                        group.max,
+                        options.join(", ")
+                    );
+                    match group.default {
+                        Some(default) => println!("; default:{}", default),
+                        None => println!(),
+                    }
+                }
             }
         }
-        Commands::Order(PlaceOrderCmd { drink_id }) => {
-            let order_id = rb.orders()?.execute(PlaceOrder { drink_id })?;
-            println!("{}", order_id);
+        Commands::Order(PlaceOrderCmd {
+            drinks,
+            customer,
+            redeem,
+        }) => {
+            let lines = drinks.into_iter().map(|DrinkArg(l)| l).collect();
+            let placed = rb.orders()?.execute(PlaceOrder {
+                lines,
+                customer_id: customer,
+                redeem_points: redeem,
+            })?;
+            // Scripts read the order id from stdout, so keep it alone there.
+            println!("{:#}", placed.order_id);
+            eprintln!("pickup:{}", placed.pickup_number);
         }
-        Commands::OrderStatus(OrderStatus { order_id }) => {
+        Commands::OrderStatus(OrderStatusCmd { order }) => {
+            let status = match order {
+                OrderKey::Id(order_id) => rb.orders()?.query(QueryOrder { order_id })?,
+                OrderKey::Pickup(pickup_number) => rb.orders()?.query(QueryPickup {
+                    pickup_number,
+                    on: SystemTime::now(),
+                })?,
+            };
+            println!(
+                "Order status: id:{:#}; pickup:{}; state:{:?}; made:{:?}; total:{}",
+                status.order_id,
+                status
+                    .pickup_number
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                status.state,
+                status.is_made,
+                price(status.total.as_ref())
+            );
+            if let Some(ready_by) = status.ready_by {
+                println!(
+                    "  ready-by:{}; overdue:{:?}",
+                    rfc3339(ready_by),
+                    status.overdue
+                );
+            }
+            if let Some(customer_id) = status.customer_id {
+                println!(
+                    "  customer:{:#}; discount:{}",
+                    customer_id,
+                    price(status.discount.as_ref())
+                );
+            }
+            for line in status.lines {
+                print!("  {:#} x{}", line.drink_id, line.quantity);
+                for modifier in line.modifiers {
+                    print!(" +{}", modifier);
+                }
+                print!(
+                    "; each:{}; made:{:?}",
+                    price(line.unit_price.as_ref()),
+                    line.fulfilled
+                );
+                match line.compensation {
+                    Some(compensation) => println!("; cancelled:{:?}", compensation),
+                    None => println!(),
+                }
+            }
+            for step in status.history {
+                println!("  {}: {:?}", rfc3339(step.at), step.state);
+            }
+        }
+        Commands::Orders(cmd) => {
+            let page = rb.orders()?.query(QueryOrders {
+                from: cmd.from.map(SystemTime::from),
+                to: cmd.to.map(SystemTime::from),
+                state: cmd.state,
+                drink: cmd.drink,
+                after: cmd.after,
+                limit: cmd.limit,
+            })?;
+            for order in page.orders {
+                let drinks: u32 = order.lines.iter().map(|l| l.quantity).sum();
+                println!(
+                    "{:#}: placed:{}; state:{:?}; drinks:{}; total:{}",
+                    order.order_id,
+                    rfc3339(order.order_id.untyped().timestamp()),
+                    order.state,
+                    drinks,
+                    price(order.total.as_ref())
+                );
+            }
+            if let Some(next) = page.next {
+                println!("More: --after {:#}", next);
+            }
+        }
+        Commands::Collect(CollectCmd { order_id }) => {
+            rb.orders()?.execute(CollectOrder { order_id })?;
+        }
+        Commands::Cancel(CancelCmd { order_id }) => {
+            rb.orders()?.execute(CancelOrder { order_id })?;
+        }
+        Commands::FailDrink(FailDrinkCmd { order_id, line }) => {
             let status = rb.orders()?.query(QueryOrder { order_id })?;
+            let drink_id = status
+                .lines
+                .get(line)
+                .ok_or_else(|| anyhow!("Order {} has no line {}", order_id, line))?
+                .drink_id;
+            rb.barista()?.execute(FailDrink {
+                drink_id,
+                order_id,
+                line,
+            })?;
+        }
+        Commands::Overdue(OverdueCmd {
+            escalate: true,
+            every: Some(secs),
+        }) => {
+            rb.orders()?.escalate_every(Duration::from_secs(secs))?;
+        }
+        Commands::Overdue(OverdueCmd {
+            escalate: true,
+            every: None,
+        }) => {
+            let now = SystemTime::now();
+            for order_id in rb.orders()?.execute(EscalateOverdue { now })? {
+                println!("{:#}", order_id);
+            }
+        }
+        Commands::Overdue(OverdueCmd {
+            escalate: false, ..
+        }) => {
+            let now = SystemTime::now();
+            for order in rb.orders()?.query(QueryOverdue { now })? {
+                println!(
+                    "{:#}: placed:{}; state:{:?}; ready-by:{}",
+                    order.order_id,
+                    rfc3339(order.order_id.untyped().timestamp()),
+                    order.state,
+                    order
+                        .ready_by
+                        .map(rfc3339)
+                        .unwrap_or_else(|| "-".to_string())
+                );
+            }
+        }
+        Commands::RegisterCustomer(RegisterCustomerCmd { name, email }) => {
+            let customer_id = rb.customers()?.execute(RegisterCustomer { name, email })?;
+            println!("{:#}", customer_id);
+        }
+        Commands::Customer(CustomerCmd { customer_id }) => {
+            let profile = rb.customers()?.query(QueryCustomer { customer_id })?;
             println!(
-                "Order status: id:{}; made:{:?}",
-                status.order_id, status.is_made
+                "Customer: id:{:#}; name:{}; email:{}; points:{}",
+                profile.customer_id,
+                profile.name,
+                profile.email.as_deref().unwrap_or("-"),
+                profile.points
             );
         }
         Commands::ActionOrder => {
@@ -100,7 +416,93 @@ fn main() -> Result<()> {
         Commands::ActionBarista => {
             rb.barista_worker()?.process_action()?;
         }
+        Commands::ActionPayment => {
+            rb.payment_worker()?.process_action()?;
+        }
+        Commands::ActionCustomer => {
+            rb.customer_worker()?.process_action()?;
+        }
+        Commands::OutboxStatus(OutboxStatusCmd {
+            publish_every: Some(secs),
+        }) => {
+            rb.outbox()?
+                .publish_every(&LogMetrics, Duration::from_secs(secs))?;
+        }
+        Commands::OutboxStatus(OutboxStatusCmd {
+            publish_every: None,
+        }) => {
+            for status in rb.outbox()?.query(QueryOutbox)? {
+                println!(
+                    "{}: pending:{}; oldest:{:?}",
+                    status.prefix, status.pending, status.oldest_pending_age
+                );
+            }
+        }
+        Commands::Workers => {
+            for worker in rb.workers()?.query(QueryWorkers)? {
+                println!(
+                    "{:#}: host:{}; pid:{}; prefix:{}; started:{}; heartbeat:{}; last-claimed:{}",
+                    worker.worker_id,
+                    worker.host,
+                    worker.pid,
+                    worker.prefix,
+                    rfc3339(worker.started_at),
+                    rfc3339(worker.heartbeat_at),
+                    worker.last_claimed.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        Commands::Work => {
+            rb.work()?;
+        }
+        Commands::Show(ShowCmd { id }) => {
+            let id = rb.registry().parse(&id)?;
+            match rb.show(&id)? {
+                Some(doc) => println!("{}", doc),
+                None => println!("No such document: {}", id),
+            }
+        }
     }
 
     Ok(())
 }
+
+impl FromStr for DrinkArg {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> Result<Self> {
+        let mut parts = src.split('+');
+        let drink = parts.next().unwrap_or_default();
+        let (drink_id, quantity) = match drink.rfind(':') {
+            Some(idx) => (&drink[..idx], drink[idx + 1..].parse()?),
+            None => (drink, 1),
+        };
+        let drink_id = drink_id.parse()?;
+        let modifiers = parts.map(|m| m.parse()).collect::<Result<_, _>>()?;
+        Ok(DrinkArg(LineItem {
+            drink_id,
+            quantity,
+            modifiers,
+        }))
+    }
+}
+
+impl FromStr for OrderKey {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> Result<Self> {
+        if src.contains('.') {
+            Ok(OrderKey::Id(src.parse()?))
+        } else {
+            Ok(OrderKey::Pickup(src.parse()?))
+        }
+    }
+}
+
+fn price(price: Option<&Money>) -> String {
+    price
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn rfc3339(t: SystemTime) -> String {
+    DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
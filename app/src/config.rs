@@ -8,10 +8,35 @@ use r2d2_postgres::{PostgresConnectionManager, TlsMode};
 use serde::{Deserialize, Serialize};
 
 use infra::persistence;
+use infra::untyped_ids::IdKey;
+use infra::workers::WorkerLease;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Config {
     pub postgres: PgConfig,
+    #[serde(default)]
+    pub ids: IdConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct IdConfig {
+    /// Keys hashed ids, so they are not predictable outside the deployment.
+    secret: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// Each store numbers its pickups separately.
+    pub id: String,
+    /// Starts each pickup number, eg: "A" for A042.
+    pub pickup_prefix: String,
+    /// How far back to look for overdue orders. Orders placed before then
+    /// are never listed by `rb overdue` or escalated, however late they
+    /// are; `rb orders --state` will still find them.
+    pub overdue_lookback: Duration,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default)]
@@ -22,6 +47,9 @@ pub struct PgConfig {
     max_lifetime: Option<Duration>,
     idle_timeout: Option<Duration>,
     connection_timeout: Option<Duration>,
+    batch_size: Option<usize>,
+    notification_timeout: Option<Duration>,
+    lease_ttl: Option<Duration>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -36,13 +64,30 @@ enum LogLevel {
 }
 
 impl PgConfig {
-    pub(crate) fn build(&self) -> Result<Pool<persistence::DocumentConnectionManager>> {
+    pub(crate) fn build(
+        &self,
+        lease: WorkerLease,
+    ) -> Result<Pool<persistence::DocumentConnectionManager>> {
         debug!("Build pool from {:?}", self);
 
+        let mut pending = persistence::PendingConfig::default();
+        if let Some(batch_size) = self.batch_size {
+            pending.batch_size = batch_size;
+        }
+        if let Some(notification_timeout) = self.notification_timeout {
+            pending.notification_timeout = notification_timeout;
+        }
+        if let Some(lease_ttl) = self.lease_ttl {
+            pending.lease_ttl = lease_ttl;
+        }
+        pending.check()?;
+
         let manager = persistence::DocumentConnectionManager::new(
             PostgresConnectionManager::new(&*self.url, TlsMode::None)
                 .with_context(|| "connection manager")?,
-        );
+        )
+        .pending(pending)
+        .lease(lease);
 
         let mut builder = r2d2::Pool::builder();
 
@@ -69,6 +114,25 @@ impl PgConfig {
     }
 }
 
+impl IdConfig {
+    pub(crate) fn key(&self) -> IdKey {
+        self.secret
+            .as_ref()
+            .map(|s| IdKey::from_secret(s))
+            .unwrap_or_default()
+    }
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            id: "main".to_string(),
+            pickup_prefix: "A".to_string(),
+            overdue_lookback: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct EnvLogger {
     level: Option<LogLevel>,
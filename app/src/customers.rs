@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use r2d2::{self, Pool};
+use serde::{Deserialize, Serialize};
+
+use infra::{
+    documents::{DocMeta, HasMeta, MailBox},
+    ids::{Entity, Id, IdGen},
+    persistence::{ConcurrencyError, Storage, StoragePending},
+};
+
+use crate::money::{Money, MoneyError};
+use crate::orders::{Order, PointsRedeemed, RedemptionRejected};
+use crate::services::{Commandable, Queryable, Request};
+
+/// How many minor units (eg: pence) of spend earn one loyalty point.
+const SPEND_PER_POINT: i64 = 10;
+/// How many times we'll re-read a customer that changed as we updated it.
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegisterCustomer {
+    pub name: String,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryCustomer {
+    pub customer_id: Id<Customer>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CustomerProfile {
+    pub customer_id: Id<Customer>,
+    pub name: String,
+    pub email: Option<String>,
+    pub points: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CreditPoints {
+    pub customer_id: Id<Customer>,
+    pub order_id: Id<Order>,
+    pub points: u64,
+}
+
+/// Spends points against an order; the customer replies to the order with
+/// the outcome.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RedeemPoints {
+    pub customer_id: Id<Customer>,
+    pub order_id: Id<Order>,
+    pub points: u64,
+}
+
+/// Returns any points redeemed against a cancelled order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RefundPoints {
+    pub customer_id: Id<Customer>,
+    pub order_id: Id<Order>,
+}
+
+#[derive(Debug)]
+pub struct Customers<M: r2d2::ManageConnection> {
+    db: Pool<M>,
+    idgen: IdGen,
+}
+
+#[derive(Debug)]
+pub struct CustomerWorker<M: r2d2::ManageConnection, O> {
+    db: Pool<M>,
+    orders: O,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Customer {
+    #[serde(flatten)]
+    pub(super) meta: DocMeta<Customer>,
+    #[serde(flatten)]
+    pub(super) mbox: MailBox<CustomerMsg>,
+    pub(super) name: String,
+    pub(super) email: Option<String>,
+    pub(super) points: u64,
+    /// Orders we've credited, so that crediting again is harmless.
+    #[serde(default)]
+    pub(super) credited: BTreeSet<Id<Order>>,
+    #[serde(default)]
+    pub(super) redeemed: BTreeMap<Id<Order>, u64>,
+    #[serde(default)]
+    pub(super) refunded: BTreeSet<Id<Order>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub(super) enum CustomerMsg {
+    PointsRedeemed { order_id: Id<Order>, points: u64 },
+    RedemptionRejected { order_id: Id<Order> },
+}
+
+/// The points earned by spending the given amount.
+pub fn points_earned(spent: &Money) -> u64 {
+    (spent.minor.max(0) / SPEND_PER_POINT) as u64
+}
+
+/// What the given points are worth; each is worth one minor unit.
+pub fn points_value(points: u64, currency: &str) -> Result<Money, MoneyError> {
+    let minor = i64::try_from(points).map_err(|_| MoneyError::Overflow)?;
+    Ok(Money::new(currency, minor))
+}
+
+impl Customer {
+    fn new(id: Id<Customer>, name: String, email: Option<String>) -> Self {
+        let meta = DocMeta::new_with_id(id);
+        let mbox = MailBox::empty();
+        Customer {
+            meta,
+            mbox,
+            name,
+            email,
+            points: 0,
+            credited: BTreeSet::new(),
+            redeemed: BTreeMap::new(),
+            refunded: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn credit(&mut self, order_id: Id<Order>, points: u64) {
+        if self.credited.insert(order_id) {
+            self.points += points;
+        }
+    }
+
+    pub(crate) fn redeem(&mut self, order_id: Id<Order>, points: u64) {
+        if let Some(&points) = self.redeemed.get(&order_id) {
+            // Already redeemed, but the order may not have heard.
+            self.mbox
+                .send(CustomerMsg::PointsRedeemed { order_id, points });
+            return;
+        }
+        if self.refunded.contains(&order_id) || points > self.points {
+            self.mbox.send(CustomerMsg::RedemptionRejected { order_id });
+            return;
+        }
+        self.points -= points;
+        self.redeemed.insert(order_id, points);
+        self.mbox
+            .send(CustomerMsg::PointsRedeemed { order_id, points });
+    }
+
+    /// Also prevents any later redemption against the order.
+    pub(crate) fn refund(&mut self, order_id: Id<Order>) {
+        if !self.refunded.insert(order_id) {
+            return;
+        }
+        if let Some(points) = self.redeemed.get(&order_id) {
+            self.points += points;
+        }
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Customers<M> {
+    pub fn new(db: Pool<M>, idgen: IdGen) -> Result<Self> {
+        Ok(Customers { db, idgen })
+    }
+
+    /// Loads, updates and saves the customer, starting afresh if someone
+    /// else saved it in the meantime.
+    fn update<F: Fn(&mut Customer)>(&self, customer_id: Id<Customer>, f: F) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut customer = docs
+                .load(&customer_id)?
+                .ok_or_else(|| anyhow!("Customer not found? id:{}", customer_id))?;
+            f(&mut customer);
+            match docs.save(&mut customer) {
+                Ok(()) => {
+                    debug!("Saved {:?}", customer);
+                    return Ok(());
+                }
+                Err(e)
+                    if attempts < MAX_ATTEMPTS
+                        && e.root_cause().downcast_ref::<ConcurrencyError>().is_some() =>
+                {
+                    warn!("Customer {} changed as we saved; retrying", customer_id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + StoragePending + Send + 'static,
+        O: Commandable<PointsRedeemed> + Commandable<RedemptionRejected>,
+    > CustomerWorker<M, O>
+{
+    pub fn new(db: Pool<M>, orders: O) -> Result<Self> {
+        Ok(CustomerWorker { db, orders })
+    }
+
+    pub fn process_action(&self) -> Result<()> {
+        self.db
+            .get()?
+            .subscribe(|doc: &mut Customer| self.handle(doc))?;
+        Ok(())
+    }
+
+    pub fn handle(&self, doc: &mut Customer) -> Result<()> {
+        info!("Found pending document: {:?}", doc);
+        while let Some(act) = doc.mbox.take_one() {
+            self.handle_customer_action(act)?;
+        }
+        Ok(())
+    }
+
+    fn handle_customer_action(&self, action: CustomerMsg) -> Result<()> {
+        info!("Action: {:?}", action);
+        match action {
+            CustomerMsg::PointsRedeemed { order_id, points } => {
+                self.orders.execute(PointsRedeemed { order_id, points })?
+            }
+            CustomerMsg::RedemptionRejected { order_id } => {
+                self.orders.execute(RedemptionRejected { order_id })?
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Request for RegisterCustomer {
+    type Resp = Id<Customer>;
+}
+
+impl Request for QueryCustomer {
+    type Resp = CustomerProfile;
+}
+
+impl Request for CreditPoints {
+    type Resp = ();
+}
+
+impl Request for RedeemPoints {
+    type Resp = ();
+}
+
+impl Request for RefundPoints {
+    type Resp = ();
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<RegisterCustomer> for Customers<M>
+{
+    fn execute(&self, RegisterCustomer { name, email }: RegisterCustomer) -> Result<Id<Customer>> {
+        let mut customer = Customer::new(self.idgen.generate(), name, email);
+        self.db.get()?.save(&mut customer)?;
+        info!("Registered customer: {}", customer.meta.id);
+        Ok(customer.meta.id)
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<CreditPoints> for Customers<M>
+{
+    fn execute(&self, credit: CreditPoints) -> Result<()> {
+        let CreditPoints {
+            customer_id,
+            order_id,
+            points,
+        } = credit;
+        self.update(customer_id, |c| c.credit(order_id, points))
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<RedeemPoints> for Customers<M>
+{
+    fn execute(&self, redeem: RedeemPoints) -> Result<()> {
+        let RedeemPoints {
+            customer_id,
+            order_id,
+            points,
+        } = redeem;
+        self.update(customer_id, |c| c.redeem(order_id, points))
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<RefundPoints> for Customers<M>
+{
+    fn execute(
+        &self,
+        RefundPoints {
+            customer_id,
+            order_id,
+        }: RefundPoints,
+    ) -> Result<()> {
+        self.update(customer_id, |c| c.refund(order_id))
+    }
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Queryable<QueryCustomer> for Customers<M>
+{
+    fn query(&self, QueryCustomer { customer_id }: QueryCustomer) -> Result<CustomerProfile> {
+        let customer: Customer = self
+            .db
+            .get()?
+            .load(&customer_id)?
+            .ok_or_else(|| anyhow!("Customer not found? id:{}", customer_id))?;
+        let Customer {
+            name,
+            email,
+            points,
+            ..
+        } = customer;
+        Ok(CustomerProfile {
+            customer_id,
+            name,
+            email,
+            points,
+        })
+    }
+}
+
+impl Entity for Customer {
+    const PREFIX: &'static str = "customer";
+}
+
+impl HasMeta for Customer {
+    fn meta(&self) -> &DocMeta<Self> {
+        &self.meta
+    }
+    fn meta_mut(&mut self) -> &mut DocMeta<Self> {
+        &mut self.meta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MemStore;
+
+    fn customer() -> Customer {
+        Customer::new(IdGen::new().generate(), "Dave".to_string(), None)
+    }
+
+    #[test]
+    fn should_credit_each_order_once() {
+        let mut customer = customer();
+        let order_id = IdGen::new().generate();
+
+        customer.credit(order_id, 30);
+        customer.credit(order_id, 30);
+
+        assert_eq!(customer.points, 30);
+    }
+
+    #[test]
+    fn should_redeem_points_once_per_order() {
+        let mut customer = customer();
+        let idgen = IdGen::new();
+        customer.credit(idgen.generate(), 100);
+        let order_id = idgen.generate();
+
+        customer.redeem(order_id, 40);
+        customer.redeem(order_id, 40);
+
+        assert_eq!(customer.points, 60);
+        assert_eq!(
+            customer.mbox.take_one(),
+            Some(CustomerMsg::PointsRedeemed {
+                order_id,
+                points: 40
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_redeeming_more_than_balance() {
+        let mut customer = customer();
+        let order_id = IdGen::new().generate();
+
+        customer.redeem(order_id, 1);
+
+        assert_eq!(customer.points, 0);
+        assert_eq!(
+            customer.mbox.take_one(),
+            Some(CustomerMsg::RedemptionRejected { order_id })
+        );
+    }
+
+    #[test]
+    fn refund_should_restore_points_and_block_late_redemption() {
+        let mut customer = customer();
+        let idgen = IdGen::new();
+        customer.credit(idgen.generate(), 100);
+        let redeemed = idgen.generate();
+        let unredeemed = idgen.generate();
+
+        customer.redeem(redeemed, 40);
+        customer.refund(redeemed);
+        customer.refund(redeemed);
+        customer.refund(unredeemed);
+        customer.redeem(unredeemed, 10);
+
+        assert_eq!(customer.points, 100);
+    }
+
+    #[test]
+    fn should_earn_and_value_points() {
+        assert_eq!(points_earned(&Money::new("GBP", 1095)), 109);
+        assert_eq!(points_earned(&Money::new("GBP", -50)), 0);
+        assert_eq!(points_value(250, "GBP"), Ok(Money::new("GBP", 250)));
+        assert_eq!(points_value(u64::MAX, "GBP"), Err(MoneyError::Overflow));
+    }
+
+    #[test]
+    fn update_should_retry_when_the_customer_changes() {
+        let store = MemStore::default();
+        let customers = Customers::new(store.pool(), IdGen::new()).expect("customers");
+        let customer_id = customers
+            .execute(RegisterCustomer {
+                name: "Dave".to_string(),
+                email: None,
+            })
+            .expect("register");
+        let order_id = IdGen::new().generate();
+
+        store.conflict_next(MAX_ATTEMPTS - 1);
+        customers
+            .execute(CreditPoints {
+                customer_id,
+                order_id,
+                points: 30,
+            })
+            .expect("credit");
+
+        let profile = customers
+            .query(QueryCustomer { customer_id })
+            .expect("query");
+        assert_eq!(profile.points, 30);
+    }
+
+    #[test]
+    fn update_should_give_up_after_max_attempts() {
+        let store = MemStore::default();
+        let customers = Customers::new(store.pool(), IdGen::new()).expect("customers");
+        let customer_id = customers
+            .execute(RegisterCustomer {
+                name: "Dave".to_string(),
+                email: None,
+            })
+            .expect("register");
+        let order_id = IdGen::new().generate();
+
+        store.conflict_next(MAX_ATTEMPTS);
+        let err = customers
+            .execute(CreditPoints {
+                customer_id,
+                order_id,
+                points: 30,
+            })
+            .expect_err("credit");
+
+        assert!(err
+            .root_cause()
+            .downcast_ref::<ConcurrencyError>()
+            .is_some());
+        let profile = customers
+            .query(QueryCustomer { customer_id })
+            .expect("query");
+        assert_eq!(profile.points, 0);
+    }
+}
@@ -3,26 +3,51 @@ use log::*;
 
 use infra::ids;
 use infra::persistence::DocumentConnectionManager;
+use infra::registry::Registry;
+use infra::supervisor::Supervisor;
+use infra::workers::WorkerLease;
 
 pub mod barista;
 pub mod config;
+pub mod customers;
 pub mod menu;
+pub mod money;
 pub mod orders;
+pub mod outbox;
+pub mod payments;
 pub mod services;
+pub mod staff;
+#[cfg(test)]
+mod testing;
+pub mod workers;
+
+/// Payments as taken by this deployment.
+pub type Payments = payments::Payments<DocumentConnectionManager, payments::FakeProvider>;
+
+pub type OrderWorker = orders::OrderWorker<
+    DocumentConnectionManager,
+    barista::Barista<DocumentConnectionManager>,
+    Payments,
+    customers::Customers<DocumentConnectionManager>,
+    staff::LogAlerts,
+>;
 
 #[derive(Clone)]
 pub struct RustBucks {
     db: r2d2::Pool<DocumentConnectionManager>,
     idgen: ids::IdGen,
+    store: config::StoreConfig,
 }
 
 impl RustBucks {
-    pub fn new(config: &config::Config) -> Result<Self, Error> {
-        let db = config.postgres.build()?;
+    pub fn new(config: &config::Config, idgen: ids::IdGen) -> Result<Self, Error> {
+        let db = config
+            .postgres
+            .build(WorkerLease::for_current_process(&idgen))?;
+        let idgen = idgen.keyed(config.ids.key());
+        let store = config.store.clone();
 
-        let idgen = ids::IdGen::new();
-
-        Ok(RustBucks { db, idgen })
+        Ok(RustBucks { db, idgen, store })
     }
 
     pub fn setup(&self) -> Result<()> {
@@ -35,18 +60,56 @@ impl RustBucks {
     }
 
     pub fn menu(&self) -> Result<menu::Menu<DocumentConnectionManager>> {
-        menu::Menu::new(self.db.clone())
+        menu::Menu::new(self.db.clone(), self.idgen.clone())
+    }
+    pub fn outbox(&self) -> Result<outbox::Outbox<DocumentConnectionManager>> {
+        outbox::Outbox::new(self.db.clone())
+    }
+    pub fn workers(&self) -> Result<workers::Workers<DocumentConnectionManager>> {
+        workers::Workers::new(self.db.clone())
     }
     pub fn orders(&self) -> Result<orders::Orders<DocumentConnectionManager>> {
-        orders::Orders::new(self.db.clone(), self.idgen.clone())
+        orders::Orders::new(self.db.clone(), self.idgen.clone(), self.store.clone())
+    }
+
+    pub fn order_worker(&self) -> Result<OrderWorker> {
+        orders::OrderWorker::new(
+            self.db.clone(),
+            self.barista()?,
+            self.payments()?,
+            self.customers()?,
+            staff::LogAlerts,
+        )
+    }
+
+    pub fn payments(&self) -> Result<Payments> {
+        payments::Payments::new(self.db.clone(), payments::FakeProvider::default())
+    }
+
+    pub fn payment_worker(
+        &self,
+    ) -> Result<
+        payments::PaymentWorker<
+            DocumentConnectionManager,
+            orders::Orders<DocumentConnectionManager>,
+        >,
+    > {
+        payments::PaymentWorker::new(self.db.clone(), self.orders()?)
+    }
+
+    pub fn customers(&self) -> Result<customers::Customers<DocumentConnectionManager>> {
+        customers::Customers::new(self.db.clone(), self.idgen.clone())
     }
 
-    pub fn order_worker(
+    pub fn customer_worker(
         &self,
     ) -> Result<
-        orders::OrderWorker<DocumentConnectionManager, barista::Barista<DocumentConnectionManager>>,
+        customers::CustomerWorker<
+            DocumentConnectionManager,
+            orders::Orders<DocumentConnectionManager>,
+        >,
     > {
-        orders::OrderWorker::new(self.db.clone(), self.barista()?)
+        customers::CustomerWorker::new(self.db.clone(), self.orders()?)
     }
 
     pub fn barista(&self) -> Result<barista::Barista<DocumentConnectionManager>> {
@@ -63,4 +126,40 @@ impl RustBucks {
     > {
         barista::BaristaWorker::new(self.db.clone(), self.orders()?)
     }
+
+    /// Knows how to load and print every entity type, eg: for admin tooling.
+    pub fn registry(&self) -> Registry<r2d2::Pool<DocumentConnectionManager>> {
+        let mut registry = Registry::new();
+        registry
+            .register::<menu::Drink>()
+            .register::<menu::DrinkList>()
+            .register::<orders::Order>()
+            .register::<barista::DrinkPreparation>()
+            .register::<payments::Payment>()
+            .register::<customers::Customer>()
+            .register::<orders::PickupCounter>();
+        registry
+    }
+
+    /// Loads and pretty-prints the document with the given id, if it exists.
+    pub fn show(&self, id: &ids::AnyId) -> Result<Option<String>> {
+        self.registry().show(&self.db, id)
+    }
+
+    /// Runs the workers for every entity type in this process.
+    pub fn work(&self) -> Result<()> {
+        let order_worker = self.order_worker()?;
+        let barista_worker = self.barista_worker()?;
+        let payment_worker = self.payment_worker()?;
+        let customer_worker = self.customer_worker()?;
+
+        let mut supervisor = Supervisor::new();
+        supervisor
+            .register(|doc: &mut orders::Order| order_worker.handle(doc))
+            .register(|doc: &mut barista::DrinkPreparation| barista_worker.handle(doc))
+            .register(|doc: &mut payments::Payment| payment_worker.handle(doc))
+            .register(|doc: &mut customers::Customer| customer_worker.handle(doc));
+
+        supervisor.run(&self.db)
+    }
 }
@@ -2,11 +2,16 @@ use anyhow::{Context, Result};
 use log::*;
 use r2d2::Pool;
 
-use infra::{documents::HasMeta, ids::Id, persistence::Storage};
+use infra::{
+    documents::HasMeta,
+    ids::{Id, IdGen},
+    persistence::Storage,
+};
 
 mod models;
-pub use models::{Drink, DrinkList};
+pub use models::{Drink, DrinkList, Modifier, ModifierError, ModifierGroup, PriceError};
 
+use crate::money::Money;
 use crate::services::{Queryable, Request};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -15,33 +20,77 @@ pub struct ShowMenu;
 #[derive(Debug)]
 pub struct Menu<M: r2d2::ManageConnection> {
     db: Pool<M>,
+    idgen: IdGen,
 }
 
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Menu<M> {
-    pub fn new(db: Pool<M>) -> Result<Self> {
-        Ok(Menu { db })
+    pub fn new(db: Pool<M>, idgen: IdGen) -> Result<Self> {
+        Ok(Menu { db, idgen })
     }
 
+    /// Creates the drinks and drink list under their current ids. Documents
+    /// created under legacy hashed ids are left in place, so that existing
+    /// orders that refer to them still resolve, but are priced alongside
+    /// their replacements, so that they can still be ordered.
     pub fn setup(&self) -> Result<()> {
         let conn = self.db.get()?;
-        Self::insert(&conn, "Umbrella").with_context(|| "insert umbrella")?;
-        Self::insert(&conn, "Fnordy").with_context(|| "insert fnordy")?;
+        let gbp = |minor| Money::new("GBP", minor);
+        let size = ModifierGroup::new("size", &["small", "regular", "large"], Some("regular"), 1)
+            .priced("small", gbp(-30))
+            .priced("large", gbp(50));
+        let milk = ModifierGroup::new("milk", &["whole", "skim", "oat", "soy"], Some("whole"), 1)
+            .priced("oat", gbp(40))
+            .priced("soy", gbp(40));
+        let shots = ModifierGroup::new("shots", &["extra"], None, 3).priced("extra", gbp(60));
+        let syrups = ModifierGroup::new("syrup", &["vanilla", "caramel", "hazelnut"], None, 2)
+            .priced("vanilla", gbp(45))
+            .priced("caramel", gbp(45))
+            .priced("hazelnut", gbp(45));
+
+        self.insert(
+            &conn,
+            "Umbrella",
+            gbp(320),
+            vec![size.clone(), milk, shots, syrups.clone()],
+        )
+        .with_context(|| "insert umbrella")?;
+        self.insert(&conn, "Fnordy", gbp(275), vec![size, syrups])
+            .with_context(|| "insert fnordy")?;
         Ok(())
     }
 
-    fn insert(docs: &D, name: &str) -> Result<()> {
+    fn insert(
+        &self,
+        docs: &D,
+        name: &str,
+        price: Money,
+        modifiers: Vec<ModifierGroup>,
+    ) -> Result<()> {
         let drink = {
-            let id = Id::hashed(name);
+            let id = self.idgen.hashed(name);
             let mut drink = docs
                 .load(&id)
                 .with_context(|| "load drink")?
                 .unwrap_or_else(|| Drink::new(id, name));
+            drink.price = Some(price.clone());
+            drink.modifiers = modifiers.clone();
             docs.save(&mut drink).with_context(|| "Save drink")?;
             drink
         };
 
+        let legacy: Option<Drink> = docs
+            .load(&Id::legacy_hashed(name))
+            .with_context(|| "load legacy drink")?;
+        if let Some(mut legacy) = legacy {
+            legacy.price = Some(price);
+            legacy.modifiers = modifiers;
+            docs.save(&mut legacy)
+                .with_context(|| "Save legacy drink")?;
+            debug!("Priced legacy drink: {:?}", legacy);
+        }
+
         let list = {
-            let id = DrinkList::id();
+            let id = DrinkList::id(&self.idgen);
             let mut list: DrinkList = docs
                 .load(&id)
                 .with_context(|| "load list")?
@@ -65,7 +114,12 @@ impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Que
 {
     fn query(&self, _query: ShowMenu) -> Result<Vec<Drink>> {
         let conn = self.db.get()?;
-        let list = conn.load(&DrinkList::id())?.expect("Missing drink list");
+        let list = match conn.load(&DrinkList::id(&self.idgen))? {
+            Some(list) => list,
+            None => conn
+                .load(&DrinkList::legacy_id())?
+                .expect("Missing drink list"),
+        };
 
         let mut res = Vec::new();
         for d in list.drinks.iter() {
@@ -77,3 +131,23 @@ impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Que
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MemStore;
+
+    #[test]
+    fn setup_should_price_drinks_under_legacy_ids() {
+        let store = MemStore::default();
+        let id = Id::legacy_hashed("Umbrella");
+        store.save(&mut Drink::new(id, "Umbrella")).expect("save");
+        let menu = Menu::new(store.pool(), IdGen::new()).expect("menu");
+
+        menu.setup().expect("setup");
+
+        let legacy: Drink = store.load(&id).expect("load").expect("drink");
+        assert_eq!(legacy.price, Some(Money::new("GBP", 320)));
+        assert_eq!(legacy.modifiers.len(), 4);
+    }
+}
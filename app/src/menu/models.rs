@@ -1,16 +1,66 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
 
 use infra::documents::{DocMeta, HasMeta};
 use serde::{Deserialize, Serialize};
 
 use infra::ids::Entity;
-use infra::ids::Id;
+use infra::ids::{Id, IdGen};
+
+use crate::money::{Money, MoneyError};
 
 #[derive(Deserialize, Serialize, Debug, Clone, Hash)]
 pub struct Drink {
     #[serde(flatten)]
     meta: DocMeta<Drink>,
     pub name: String,
+    #[serde(default)]
+    pub price: Option<Money>,
+    /// The ways this drink may be customized, eg: size or milk.
+    #[serde(default)]
+    pub modifiers: Vec<ModifierGroup>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct ModifierGroup {
+    pub name: String,
+    pub options: Vec<String>,
+    /// Used when none of the group's options are chosen.
+    #[serde(default)]
+    pub default: Option<String>,
+    /// How many options may be chosen, counting repeats, eg: two extra shots.
+    pub max: u32,
+    /// What each option adds to the price of the drink, if anything.
+    #[serde(default)]
+    pub prices: BTreeMap<String, Money>,
+}
+
+/// An option chosen from one of a drink's modifier groups, eg: `milk=oat`.
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Modifier {
+    pub group: String,
+    pub option: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, err_derive::Error)]
+pub enum ModifierError {
+    #[error(display = "No modifier group {:?} for {}", _1, _0)]
+    UnknownGroup(String, String),
+    #[error(display = "No option {:?} in modifier group {:?}", _1, _0)]
+    UnknownOption(String, String),
+    #[error(display = "At most {} choices of {:?}", _1, _0)]
+    TooMany(String, u32),
+    #[error(display = "Modifiers look like group=option, not {:?}", _0)]
+    Unparseable(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, err_derive::Error)]
+pub enum PriceError {
+    #[error(display = "{} has no price", _0)]
+    Unpriced(String),
+    #[error(display = "Cannot price drink")]
+    Money(#[error(source)] MoneyError),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -24,7 +74,117 @@ impl Drink {
     pub(super) fn new(id: Id<Drink>, name: &str) -> Self {
         let meta = DocMeta::new_with_id(id);
         let name = name.to_string();
-        Drink { meta, name }
+        let price = None;
+        let modifiers = Vec::new();
+        Drink {
+            meta,
+            name,
+            price,
+            modifiers,
+        }
+    }
+
+    /// The price of one of this drink with the given modifiers, which should
+    /// already have been checked by `customize`.
+    pub fn price_with(&self, modifiers: &[Modifier]) -> Result<Money, PriceError> {
+        let mut price = self
+            .price
+            .clone()
+            .ok_or_else(|| PriceError::Unpriced(self.name.clone()))?;
+        for modifier in modifiers {
+            let surcharge = self
+                .modifiers
+                .iter()
+                .filter(|g| g.name == modifier.group)
+                .filter_map(|g| g.prices.get(&modifier.option))
+                .next();
+            if let Some(surcharge) = surcharge {
+                price = price.checked_add(surcharge)?;
+            }
+        }
+        Ok(price)
+    }
+
+    /// Checks the chosen modifiers against those this drink allows, and
+    /// fills in the defaults for any groups left unchosen.
+    pub fn customize(&self, chosen: &[Modifier]) -> Result<Vec<Modifier>, ModifierError> {
+        let mut counts = BTreeMap::new();
+        for modifier in chosen {
+            let group = self
+                .modifiers
+                .iter()
+                .find(|g| g.name == modifier.group)
+                .ok_or_else(|| {
+                    ModifierError::UnknownGroup(self.name.clone(), modifier.group.clone())
+                })?;
+            if !group.options.contains(&modifier.option) {
+                return Err(ModifierError::UnknownOption(
+                    group.name.clone(),
+                    modifier.option.clone(),
+                ));
+            }
+            let count = counts.entry(&group.name).or_insert(0);
+            *count += 1;
+            if *count > group.max {
+                return Err(ModifierError::TooMany(group.name.clone(), group.max));
+            }
+        }
+
+        let mut modifiers = chosen.to_vec();
+        for group in self.modifiers.iter() {
+            if let (false, Some(option)) = (counts.contains_key(&group.name), &group.default) {
+                modifiers.push(Modifier::new(&group.name, option));
+            }
+        }
+        modifiers.sort();
+        Ok(modifiers)
+    }
+}
+
+impl ModifierGroup {
+    pub(super) fn new(name: &str, options: &[&str], default: Option<&str>, max: u32) -> Self {
+        let name = name.to_string();
+        let options = options.iter().map(|o| o.to_string()).collect();
+        let default = default.map(|d| d.to_string());
+        let prices = BTreeMap::new();
+        ModifierGroup {
+            name,
+            options,
+            default,
+            max,
+            prices,
+        }
+    }
+
+    pub(super) fn priced(mut self, option: &str, price: Money) -> Self {
+        self.prices.insert(option.to_string(), price);
+        self
+    }
+}
+
+impl Modifier {
+    pub fn new(group: &str, option: &str) -> Self {
+        let group = group.to_string();
+        let option = option.to_string();
+        Modifier { group, option }
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}={}", self.group, self.option)
+    }
+}
+
+impl FromStr for Modifier {
+    type Err = ModifierError;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src.find('=') {
+            Some(idx) if idx > 0 && idx + 1 < src.len() => {
+                Ok(Modifier::new(&src[..idx], &src[idx + 1..]))
+            }
+            _ => Err(ModifierError::Unparseable(src.to_string())),
+        }
     }
 }
 
@@ -34,8 +194,12 @@ impl DrinkList {
         let drinks = BTreeSet::new();
         DrinkList { meta, drinks }
     }
-    pub(super) fn id() -> Id<DrinkList> {
-        Id::hashed("DrinkList")
+    pub(super) fn id(idgen: &IdGen) -> Id<DrinkList> {
+        idgen.hashed("DrinkList")
+    }
+    /// Where the list lived before hashed ids were namespaced and keyed.
+    pub(super) fn legacy_id() -> Id<DrinkList> {
+        Id::legacy_hashed("DrinkList")
     }
 }
 
@@ -64,3 +228,86 @@ impl HasMeta for DrinkList {
         &mut self.meta
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn latte() -> Drink {
+        let mut drink = Drink::new(IdGen::new().hashed("Latte"), "Latte");
+        drink.price = Some(Money::new("GBP", 300));
+        drink.modifiers = vec![
+            ModifierGroup::new("size", &["small", "large"], Some("small"), 1)
+                .priced("large", Money::new("GBP", 50)),
+            ModifierGroup::new("milk", &["whole", "oat"], Some("whole"), 1)
+                .priced("oat", Money::new("GBP", 30)),
+            ModifierGroup::new("shots", &["extra"], None, 2).priced("extra", Money::new("GBP", 40)),
+        ];
+        drink
+    }
+
+    fn modifiers(src: &[&str]) -> Vec<Modifier> {
+        src.iter().map(|m| m.parse().expect("parse")).collect()
+    }
+
+    #[test]
+    fn customize_should_fill_in_defaults() {
+        assert_eq!(
+            latte().customize(&modifiers(&["milk=oat", "shots=extra"])),
+            Ok(modifiers(&["milk=oat", "shots=extra", "size=small"]))
+        );
+    }
+
+    #[test]
+    fn customize_should_reject_options_the_drink_lacks() {
+        assert_eq!(
+            latte().customize(&modifiers(&["syrup=vanilla"])),
+            Err(ModifierError::UnknownGroup(
+                "Latte".to_string(),
+                "syrup".to_string()
+            ))
+        );
+        assert_eq!(
+            latte().customize(&modifiers(&["milk=soy"])),
+            Err(ModifierError::UnknownOption(
+                "milk".to_string(),
+                "soy".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn customize_should_limit_choices_per_group() {
+        assert!(latte()
+            .customize(&modifiers(&["shots=extra", "shots=extra"]))
+            .is_ok());
+        assert_eq!(
+            latte().customize(&modifiers(&["size=small", "size=large"])),
+            Err(ModifierError::TooMany("size".to_string(), 1))
+        );
+    }
+
+    #[test]
+    fn price_should_include_modifier_surcharges() {
+        let chosen = modifiers(&["size=large", "shots=extra", "shots=extra", "milk=whole"]);
+        assert_eq!(latte().price_with(&chosen), Ok(Money::new("GBP", 430)));
+    }
+
+    #[test]
+    fn price_should_require_drink_price() {
+        let mut drink = latte();
+        drink.price = None;
+        assert_eq!(
+            drink.price_with(&[]),
+            Err(PriceError::Unpriced("Latte".to_string()))
+        );
+    }
+
+    #[test]
+    fn modifier_should_round_trip_via_string() {
+        let modifier = Modifier::new("milk", "oat");
+        assert_eq!(modifier.to_string().parse(), Ok(modifier));
+        assert!("milk".parse::<Modifier>().is_err());
+        assert!("=oat".parse::<Modifier>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// An amount of money, held in the minor units of its currency (eg: pence)
+/// so that sums are exact.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Money {
+    /// ISO 4217 currency code, eg: "GBP".
+    pub currency: String,
+    pub minor: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, err_derive::Error)]
+pub enum MoneyError {
+    #[error(display = "Cannot combine {} with {}", _0, _1)]
+    CurrencyMismatch(String, String),
+    #[error(display = "Amount out of range")]
+    Overflow,
+}
+
+impl Money {
+    pub fn new(currency: &str, minor: i64) -> Self {
+        let currency = currency.to_string();
+        Money { currency, minor }
+    }
+
+    pub fn zero(currency: &str) -> Self {
+        Self::new(currency, 0)
+    }
+
+    pub fn checked_add(&self, other: &Money) -> Result<Money, MoneyError> {
+        if self.currency != other.currency {
+            return Err(MoneyError::CurrencyMismatch(
+                self.currency.clone(),
+                other.currency.clone(),
+            ));
+        }
+        let minor = self
+            .minor
+            .checked_add(other.minor)
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(&self.currency, minor))
+    }
+
+    pub fn checked_sub(&self, other: &Money) -> Result<Money, MoneyError> {
+        let minor = other.minor.checked_neg().ok_or(MoneyError::Overflow)?;
+        self.checked_add(&Money::new(&other.currency, minor))
+    }
+
+    pub fn checked_mul(&self, n: u32) -> Result<Money, MoneyError> {
+        let minor = self
+            .minor
+            .checked_mul(i64::from(n))
+            .ok_or(MoneyError::Overflow)?;
+        Ok(Money::new(&self.currency, minor))
+    }
+
+    /// How many digits of the minor unit go after the decimal point.
+    fn exponent(&self) -> u32 {
+        match &*self.currency {
+            "JPY" | "KRW" => 0,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exponent = self.exponent();
+        let sign = if self.minor < 0 { "-" } else { "" };
+        let minor = self.minor.unsigned_abs();
+        if exponent == 0 {
+            return write!(fmt, "{}{} {}", sign, minor, self.currency);
+        }
+        let scale = 10u64.pow(exponent);
+        write!(
+            fmt,
+            "{}{}.{:0width$} {}",
+            sign,
+            minor / scale,
+            minor % scale,
+            self.currency,
+            width = exponent as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_add_same_currency() {
+        assert_eq!(
+            Money::new("GBP", 250).checked_add(&Money::new("GBP", 45)),
+            Ok(Money::new("GBP", 295))
+        );
+    }
+
+    #[test]
+    fn should_not_add_different_currencies() {
+        assert_eq!(
+            Money::new("GBP", 250).checked_add(&Money::new("EUR", 45)),
+            Err(MoneyError::CurrencyMismatch(
+                "GBP".to_string(),
+                "EUR".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn should_subtract_same_currency() {
+        assert_eq!(
+            Money::new("GBP", 250).checked_sub(&Money::new("GBP", 300)),
+            Ok(Money::new("GBP", -50))
+        );
+    }
+
+    #[test]
+    fn should_detect_overflow() {
+        assert_eq!(
+            Money::new("GBP", i64::MAX).checked_mul(2),
+            Err(MoneyError::Overflow)
+        );
+    }
+
+    #[test]
+    fn should_display_in_major_units() {
+        assert_eq!(Money::new("GBP", 305).to_string(), "3.05 GBP");
+        assert_eq!(Money::new("GBP", -5).to_string(), "-0.05 GBP");
+        assert_eq!(Money::new("JPY", 400).to_string(), "400 JPY");
+    }
+}
@@ -1,132 +1,783 @@
-use anyhow::Result;
+use std::cmp;
+use std::ops::Bound;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use log::*;
 use r2d2::Pool;
 
 use crate::{
-    barista::PrepareDrink,
-    menu::Drink,
+    barista::{CancelDrink, PrepareDrink},
+    config::StoreConfig,
+    customers::{self, CreditPoints, Customer, RedeemPoints, RefundPoints},
+    menu::{Drink, Modifier},
+    money::Money,
+    payments::{AuthorizePayment, CapturePayment, ReleasePayment},
     services::{Commandable, Queryable, Request},
+    staff::AlertStaff,
 };
 use infra::{
     ids::{Id, IdGen},
-    persistence::{Storage, StoragePending},
+    persistence::{ConcurrencyError, Storage, StoragePending},
+    supervisor,
+    untyped_ids::UntypedId,
 };
 
 mod models;
+mod pickup;
 
-pub use models::Order;
 use models::*;
+pub use models::{Compensation, Order, OrderError, OrderLine, OrderState, Transition};
+pub use pickup::{PickupCounter, PickupError, PickupNumber};
+
+/// How long we promise to take over any order, plus a little per drink.
+const READY_WITHIN: Duration = Duration::from_secs(5 * 60);
+const READY_WITHIN_PER_DRINK: Duration = Duration::from_secs(60);
+const OVERDUE_BATCH: usize = 100;
+/// How many times we'll try to take a pickup number, as other orders take
+/// them too.
+const PICKUP_ATTEMPTS: usize = 10;
+const PICKUP_BATCH: usize = 100;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PlaceOrder {
+    pub lines: Vec<LineItem>,
+    pub customer_id: Option<Id<Customer>>,
+    /// Loyalty points to spend on the order; needs a customer.
+    pub redeem_points: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OrderPlaced {
+    pub order_id: Id<Order>,
+    pub pickup_number: PickupNumber,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LineItem {
     pub drink_id: Id<Drink>,
+    pub quantity: u32,
+    pub modifiers: Vec<Modifier>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FulfillDrink {
     pub order_id: Id<Order>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CancelOrder {
+    pub order_id: Id<Order>,
+}
+
+/// The barista has started on a line of the order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DrinkStarted {
+    pub order_id: Id<Order>,
+    pub line: usize,
+}
+
+/// The barista's reply to a cancelled line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DrinkCancelled {
+    pub order_id: Id<Order>,
+    pub line: usize,
+    pub compensation: Compensation,
+}
+
+/// The barista couldn't make a line of the order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DrinkFailed {
+    pub order_id: Id<Order>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PaymentAuthorized {
+    pub order_id: Id<Order>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PaymentDeclined {
+    pub order_id: Id<Order>,
+}
+
+/// The customer's reply to a redemption.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PointsRedeemed {
+    pub order_id: Id<Order>,
+    pub points: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RedemptionRejected {
+    pub order_id: Id<Order>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CollectOrder {
+    pub order_id: Id<Order>,
+}
+
+/// Escalates each order that is overdue as of `now`, and returns them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EscalateOverdue {
+    pub now: SystemTime,
+}
+
+/// Lists orders that are still unfinished after their ready-by time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryOverdue {
+    pub now: SystemTime,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct QueryOrder {
     pub order_id: Id<Order>,
 }
+
+/// Finds the order with the given pickup number, placed in this store on
+/// the (UTC) day of `on`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryPickup {
+    pub pickup_number: PickupNumber,
+    pub on: SystemTime,
+}
+/// Lists orders placed in a time range, oldest first, optionally filtered
+/// by state and drink.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryOrders {
+    /// Orders placed at or after this time.
+    pub from: Option<SystemTime>,
+    /// Orders placed before this time.
+    pub to: Option<SystemTime>,
+    pub state: Option<OrderState>,
+    pub drink: Option<Id<Drink>>,
+    /// Continues from the `next` order of a previous page.
+    pub after: Option<Id<Order>>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OrderPage {
+    pub orders: Vec<OrderStatus>,
+    /// Where the next page starts, if there may be more orders.
+    pub next: Option<Id<Order>>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct OrderStatus {
     pub order_id: Id<Order>,
+    pub pickup_number: Option<PickupNumber>,
     pub is_made: bool,
+    pub lines: Vec<OrderLine>,
+    pub total: Option<Money>,
+    pub discount: Option<Money>,
+    pub customer_id: Option<Id<Customer>>,
+    pub state: OrderState,
+    pub history: Vec<Transition>,
+    pub ready_by: Option<SystemTime>,
+    pub overdue: bool,
 }
 
 #[derive(Debug)]
 pub struct Orders<M: r2d2::ManageConnection> {
     db: Pool<M>,
     idgen: IdGen,
+    store: StoreConfig,
 }
 
-pub struct OrderWorker<M: r2d2::ManageConnection, B> {
+pub struct OrderWorker<M: r2d2::ManageConnection, B, P, C, S> {
     db: Pool<M>,
     barista: B,
+    payments: P,
+    customers: C,
+    staff: S,
 }
 
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + StoragePending + Send + 'static>
     Orders<M>
 {
-    pub fn new(db: Pool<M>, idgen: IdGen) -> Result<Self> {
-        Ok(Orders { db, idgen })
+    /// Escalates overdue orders at each interval, backing off (up to the
+    /// interval) after a failure.
+    pub fn escalate_every(&self, interval: Duration) -> Result<()> {
+        let mut failures = 0;
+        loop {
+            let now = SystemTime::now();
+            let delay = match self.execute(EscalateOverdue { now }) {
+                Ok(escalated) => {
+                    info!("Escalated {} overdue orders", escalated.len());
+                    failures = 0;
+                    interval
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = cmp::min(supervisor::backoff(failures), interval);
+                    error!(
+                        "Escalating overdue orders failed ({} times); retrying in {:?}: {:?}",
+                        failures, delay, e
+                    );
+                    delay
+                }
+            };
+            thread::sleep(delay);
+        }
     }
 }
 
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Orders<M> {
+    pub fn new(db: Pool<M>, idgen: IdGen, store: StoreConfig) -> Result<Self> {
+        Ok(Orders { db, idgen, store })
+    }
+
+    /// Finds the orders overdue at `now`, among those placed within the
+    /// store's overdue lookback.
+    fn load_overdue(&self, docs: &D, now: SystemTime) -> Result<Vec<Order>> {
+        let since = now
+            .checked_sub(self.store.overdue_lookback)
+            .map_or(SystemTime::UNIX_EPOCH, |t| t.max(SystemTime::UNIX_EPOCH));
+        let from = UntypedId::at(since, 0)?.typed::<Order>();
+        let to = UntypedId::at(now, 0)?.typed::<Order>();
+
+        let mut overdue = Vec::new();
+        let mut cursor = None;
+        loop {
+            let lower = cursor
+                .as_ref()
+                .map_or(Bound::Included(&from), Bound::Excluded);
+            let batch: Vec<Order> = docs.load_range(lower, Bound::Excluded(&to), OVERDUE_BATCH)?;
+            let exhausted = batch.len() < OVERDUE_BATCH;
+            cursor = batch.last().map(|o| o.meta.id);
+            overdue.extend(batch.into_iter().filter(|o| o.is_overdue(now)));
+            if exhausted {
+                return Ok(overdue);
+            }
+        }
+    }
+
+    /// Takes the next pickup number for this store's day, starting over if
+    /// another order took one as we did.
+    fn next_pickup(&self, docs: &D, at: SystemTime) -> Result<PickupNumber> {
+        let (day, _, _) = utc_day(at);
+        let id = PickupCounter::id_for(&self.idgen, &self.store.id, &day);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut counter = docs
+                .load(&id)?
+                .unwrap_or_else(|| PickupCounter::new(&self.idgen, &self.store.id, &day));
+            let number = counter.next();
+            match docs.save(&mut counter) {
+                Ok(()) => return Ok(PickupNumber::new(&self.store.pickup_prefix, number)),
+                Err(e)
+                    if attempts < PICKUP_ATTEMPTS
+                        && e.root_cause().downcast_ref::<ConcurrencyError>().is_some() =>
+                {
+                    debug!("Pickup counter {} taken; retrying", id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The UTC date of the given time, and when that day starts and ends.
+fn utc_day(at: SystemTime) -> (String, SystemTime, SystemTime) {
+    let date = DateTime::<Utc>::from(at).date();
+    let start = date.and_hms(0, 0, 0);
+    let end = date.succ().and_hms(0, 0, 0);
+    (
+        date.format("%Y-%m-%d").to_string(),
+        start.into(),
+        end.into(),
+    )
+}
+
 impl<
         M: r2d2::ManageConnection<Connection = D>,
         D: Storage + StoragePending + Send + 'static,
-        B: Commandable<PrepareDrink>,
-    > OrderWorker<M, B>
+        B: Commandable<PrepareDrink> + Commandable<CancelDrink>,
+        P: Commandable<AuthorizePayment> + Commandable<CapturePayment> + Commandable<ReleasePayment>,
+        C: Commandable<RedeemPoints> + Commandable<RefundPoints> + Commandable<CreditPoints>,
+        S: Commandable<AlertStaff>,
+    > OrderWorker<M, B, P, C, S>
 {
-    pub fn new(db: Pool<M>, barista: B) -> Result<Self> {
-        Ok(OrderWorker { db, barista })
+    pub fn process_action(&self) -> Result<()> {
+        self.db
+            .get()?
+            .subscribe(|doc: &mut Order| self.handle(doc))?;
+        Ok(())
     }
+}
 
-    pub fn process_action(&self) -> Result<()> {
-        self.db.get()?.subscribe(|doc: &mut Order| {
-            info!("Found pending document: {:?}", doc);
-            while let Some(act) = doc.mbox.take_one() {
-                self.handle_order_action(act)?;
-            }
-            Ok(())
-        })?;
+impl<
+        M: r2d2::ManageConnection,
+        B: Commandable<PrepareDrink> + Commandable<CancelDrink>,
+        P: Commandable<AuthorizePayment> + Commandable<CapturePayment> + Commandable<ReleasePayment>,
+        C: Commandable<RedeemPoints> + Commandable<RefundPoints> + Commandable<CreditPoints>,
+        S: Commandable<AlertStaff>,
+    > OrderWorker<M, B, P, C, S>
+{
+    pub fn new(db: Pool<M>, barista: B, payments: P, customers: C, staff: S) -> Result<Self> {
+        Ok(OrderWorker {
+            db,
+            barista,
+            payments,
+            customers,
+            staff,
+        })
+    }
+
+    pub fn handle(&self, doc: &mut Order) -> Result<()> {
+        info!("Found pending document: {:?}", doc);
+        while let Some(act) = doc.mbox.take_one() {
+            self.handle_order_action(act)?;
+        }
         Ok(())
     }
 
     fn handle_order_action(&self, action: OrderMsg) -> Result<()> {
         info!("Action: {:?}", action);
         match action {
-            OrderMsg::DrinkRequest(drink_id, order_id) => {
-                info!("Drink req: item:{}; order:{}", drink_id, order_id);
-                self.barista.execute(PrepareDrink { drink_id, order_id })?
+            OrderMsg::DrinkRequest {
+                drink_id,
+                order_id,
+                line,
+                quantity,
+                modifiers,
+            } => {
+                info!(
+                    "Drink req: item:{}; order:{}; line:{}; quantity:{}",
+                    drink_id, order_id, line, quantity
+                );
+                self.barista.execute(PrepareDrink {
+                    drink_id,
+                    order_id,
+                    line,
+                    quantity,
+                    modifiers,
+                })?
+            }
+            OrderMsg::CancelPreparation {
+                drink_id,
+                order_id,
+                line,
+            } => {
+                info!(
+                    "Cancel drink: item:{}; order:{}; line:{}",
+                    drink_id, order_id, line
+                );
+                self.barista.execute(CancelDrink {
+                    drink_id,
+                    order_id,
+                    line,
+                })?
+            }
+            OrderMsg::AuthorizePayment { order_id, amount } => {
+                info!("Authorize payment: order:{}; amount:{:?}", order_id, amount);
+                self.payments
+                    .execute(AuthorizePayment { order_id, amount })?
+            }
+            OrderMsg::CapturePayment { order_id } => {
+                info!("Capture payment: order:{}", order_id);
+                self.payments.execute(CapturePayment { order_id })?
+            }
+            OrderMsg::ReleasePayment { order_id } => {
+                info!("Release payment: order:{}", order_id);
+                self.payments.execute(ReleasePayment { order_id })?
+            }
+            OrderMsg::RedeemPoints {
+                customer_id,
+                order_id,
+                points,
+            } => {
+                info!(
+                    "Redeem points: customer:{}; order:{}; points:{}",
+                    customer_id, order_id, points
+                );
+                self.customers.execute(RedeemPoints {
+                    customer_id,
+                    order_id,
+                    points,
+                })?
+            }
+            OrderMsg::RefundPoints {
+                customer_id,
+                order_id,
+            } => {
+                info!(
+                    "Refund points: customer:{}; order:{}",
+                    customer_id, order_id
+                );
+                self.customers.execute(RefundPoints {
+                    customer_id,
+                    order_id,
+                })?
+            }
+            OrderMsg::CreditPoints {
+                customer_id,
+                order_id,
+                points,
+            } => {
+                info!(
+                    "Credit points: customer:{}; order:{}; points:{}",
+                    customer_id, order_id, points
+                );
+                self.customers.execute(CreditPoints {
+                    customer_id,
+                    order_id,
+                    points,
+                })?
+            }
+            OrderMsg::AlertStaff { order_id, ready_by } => {
+                info!("Alert staff: order:{}", order_id);
+                self.staff.execute(AlertStaff { order_id, ready_by })?
             }
         };
         Ok(())
     }
 }
 
+/// When an order placed at the given time should be ready.
+fn ready_by(now: SystemTime, lines: &[OrderLine]) -> Result<SystemTime, OrderError> {
+    lines
+        .iter()
+        .try_fold(0u32, |drinks, l| drinks.checked_add(l.quantity))
+        .and_then(|drinks| READY_WITHIN_PER_DRINK.checked_mul(drinks))
+        .and_then(|per_drink| READY_WITHIN.checked_add(per_drink))
+        .and_then(|within| now.checked_add(within))
+        .ok_or(OrderError::TooManyDrinks)
+}
+
 impl Request for PlaceOrder {
-    type Resp = Id<Order>;
+    type Resp = OrderPlaced;
 }
 
 impl Request for FulfillDrink {
     type Resp = ();
 }
 
+impl Request for CancelOrder {
+    type Resp = ();
+}
+
+impl Request for DrinkStarted {
+    type Resp = ();
+}
+
+impl Request for DrinkCancelled {
+    type Resp = ();
+}
+
+impl Request for DrinkFailed {
+    type Resp = ();
+}
+
+impl Request for PaymentAuthorized {
+    type Resp = ();
+}
+
+impl Request for PaymentDeclined {
+    type Resp = ();
+}
+
+impl Request for PointsRedeemed {
+    type Resp = ();
+}
+
+impl Request for RedemptionRejected {
+    type Resp = ();
+}
+
+impl Request for CollectOrder {
+    type Resp = ();
+}
+
+impl Request for EscalateOverdue {
+    type Resp = Vec<Id<Order>>;
+}
+
+impl Request for QueryOverdue {
+    type Resp = Vec<OrderStatus>;
+}
+
 impl Request for QueryOrder {
     type Resp = OrderStatus;
 }
 
+impl Request for QueryPickup {
+    type Resp = OrderStatus;
+}
+
+impl Request for QueryOrders {
+    type Resp = OrderPage;
+}
+
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Commandable<PlaceOrder>
     for Orders<M>
 {
-    fn execute(&self, order: PlaceOrder) -> Result<Id<Order>> {
+    fn execute(&self, order: PlaceOrder) -> Result<OrderPlaced> {
+        if order.lines.is_empty() {
+            return Err(anyhow!("Order has no drinks"));
+        }
+        if let Some(item) = order.lines.iter().find(|l| l.quantity == 0) {
+            return Err(anyhow!("Zero quantity for drink: {}", item.drink_id));
+        }
+        if order.redeem_points > 0 && order.customer_id.is_none() {
+            return Err(anyhow!("Only customers can redeem points"));
+        }
+
         let docs = self.db.get()?;
-        let mut order = Order::for_drink(order.drink_id, self.idgen.generate());
+        let mut lines = Vec::new();
+        let mut total: Option<Money> = None;
+        for item in order.lines {
+            let drink: Drink = docs
+                .load(&item.drink_id)?
+                .ok_or_else(|| anyhow!("No such drink: {}", item.drink_id))?;
+            let mut line = OrderLine::new(item.drink_id, item.quantity);
+            line.modifiers = drink.customize(&item.modifiers)?;
+
+            let unit_price = drink.price_with(&line.modifiers)?;
+            let line_total = unit_price.checked_mul(item.quantity)?;
+            total = Some(match total {
+                Some(total) => total.checked_add(&line_total)?,
+                None => line_total,
+            });
+            line.unit_price = Some(unit_price);
+            lines.push(line);
+        }
+        if order.redeem_points > 0 {
+            let total = total
+                .as_ref()
+                .ok_or_else(|| anyhow!("Order has no price"))?;
+            let value = customers::points_value(order.redeem_points, &total.currency)?;
+            if value.minor > total.minor {
+                return Err(anyhow!(
+                    "Points worth {} exceed order total {}",
+                    value,
+                    total
+                ));
+            }
+        }
+        let customer_id = order.customer_id;
+        let redeem_points = order.redeem_points;
+        let now = SystemTime::now();
+        let ready_by = ready_by(now, &lines)?;
+        let order_id = self.idgen.generate();
+        let mut order = Order::for_lines(lines, total, order_id, now);
+        order.ready_by = Some(ready_by);
+        if let Some(customer_id) = customer_id {
+            let _: Customer = docs
+                .load(&customer_id)?
+                .ok_or_else(|| anyhow!("No such customer: {}", customer_id))?;
+            order.for_customer(customer_id, redeem_points);
+        }
+        // We find orders by pickup number amongst those whose ids fall on
+        // the same day, so number them by that day too.
+        let pickup_number = self.next_pickup(&docs, order_id.untyped().timestamp())?;
+        order.store = Some(self.store.id.clone());
+        order.pickup_number = Some(pickup_number.clone());
         docs.save(&mut order)?;
         debug!("Saved {:?}", order);
-        info!("Order placed: {}", order.meta.id);
-        Ok(order.meta.id)
+        info!("Order placed: {}; pickup:{}", order.meta.id, pickup_number);
+        Ok(OrderPlaced {
+            order_id: order.meta.id,
+            pickup_number,
+        })
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<DrinkStarted> for Orders<M>
+{
+    fn execute(&self, DrinkStarted { order_id, line }: DrinkStarted) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.mark_started(line, SystemTime::now())?;
+        docs.save(&mut order)?;
+        Ok(())
     }
 }
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
     Commandable<FulfillDrink> for Orders<M>
 {
-    fn execute(&self, FulfillDrink { order_id }: FulfillDrink) -> Result<()> {
+    fn execute(&self, FulfillDrink { order_id, line }: FulfillDrink) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.mark_fulfilled(line, SystemTime::now())?;
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<CancelOrder> for Orders<M>
+{
+    fn execute(&self, CancelOrder { order_id }: CancelOrder) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.cancel(SystemTime::now())?;
+        docs.save(&mut order)?;
+        info!("Order cancelled: {}", order_id);
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<DrinkCancelled> for Orders<M>
+{
+    fn execute(
+        &self,
+        DrinkCancelled {
+            order_id,
+            line,
+            compensation,
+        }: DrinkCancelled,
+    ) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.record_compensation(line, compensation);
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<DrinkFailed> for Orders<M>
+{
+    fn execute(&self, DrinkFailed { order_id, line }: DrinkFailed) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        warn!("Preparation failed: order:{}; line:{}", order_id, line);
+        order.preparation_failed(SystemTime::now())?;
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<PaymentAuthorized> for Orders<M>
+{
+    fn execute(&self, PaymentAuthorized { order_id }: PaymentAuthorized) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.payment_authorized(SystemTime::now())?;
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<PaymentDeclined> for Orders<M>
+{
+    fn execute(&self, PaymentDeclined { order_id }: PaymentDeclined) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        info!("Payment declined: order:{}", order_id);
+        order.payment_declined(SystemTime::now())?;
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<PointsRedeemed> for Orders<M>
+{
+    fn execute(&self, PointsRedeemed { order_id, points }: PointsRedeemed) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.points_redeemed(points)?;
+        docs.save(&mut order)?;
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<RedemptionRejected> for Orders<M>
+{
+    fn execute(&self, RedemptionRejected { order_id }: RedemptionRejected) -> Result<()> {
         let docs = self.db.get()?;
         let mut order = docs
             .load(&order_id)?
-            .ok_or_else(|| anyhow::anyhow!("Order not found? id:{}", order_id))?;
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
 
-        order.mark_fulfilled();
+        info!("Points redemption rejected: order:{}", order_id);
+        order.redemption_rejected(SystemTime::now())?;
         docs.save(&mut order)?;
         Ok(())
     }
 }
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<CollectOrder> for Orders<M>
+{
+    fn execute(&self, CollectOrder { order_id }: CollectOrder) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut order = docs
+            .load(&order_id)?
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        order.transition(OrderState::Collected, SystemTime::now())?;
+        docs.save(&mut order)?;
+        info!("Order collected: {}", order_id);
+        Ok(())
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static>
+    Commandable<EscalateOverdue> for Orders<M>
+{
+    fn execute(&self, EscalateOverdue { now }: EscalateOverdue) -> Result<Vec<Id<Order>>> {
+        let docs = self.db.get()?;
+        let mut escalated = Vec::new();
+        for mut order in self.load_overdue(&docs, now)? {
+            if !order.escalate(now)? {
+                continue;
+            }
+            match docs.save(&mut order) {
+                Ok(()) => {
+                    warn!("Order overdue: {}", order.meta.id);
+                    escalated.push(order.meta.id);
+                }
+                // It's moved on since we looked; we'll check it again next time.
+                Err(e) if e.root_cause().downcast_ref::<ConcurrencyError>().is_some() => {
+                    debug!("Order changed while escalating: {}", order.meta.id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(escalated)
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Queryable<QueryOverdue>
+    for Orders<M>
+{
+    fn query(&self, QueryOverdue { now }: QueryOverdue) -> Result<Vec<OrderStatus>> {
+        let docs = self.db.get()?;
+        let overdue = self.load_overdue(&docs, now)?;
+        Ok(overdue
+            .into_iter()
+            .map(|o| OrderStatus::at(o, now))
+            .collect())
+    }
+}
 impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Queryable<QueryOrder>
     for Orders<M>
 {
@@ -134,11 +785,534 @@ impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Que
         let docs = self.db.get()?;
         let order = docs
             .load(&order_id)?
-            .ok_or_else(|| anyhow::anyhow!("Order not found? id:{}", order_id))?;
-        let Order { is_made, .. } = order;
+            .ok_or_else(|| anyhow!("Order not found? id:{}", order_id))?;
+
+        Ok(OrderStatus::at(order, SystemTime::now()))
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Queryable<QueryPickup>
+    for Orders<M>
+{
+    fn query(&self, QueryPickup { pickup_number, on }: QueryPickup) -> Result<OrderStatus> {
+        let (day, start, end) = utc_day(on);
+        let from = UntypedId::at(start, 0)?.typed::<Order>();
+        let to = UntypedId::at(end, 0)?.typed::<Order>();
+
+        let docs = self.db.get()?;
+        let mut cursor = None;
+        loop {
+            let lower = cursor
+                .as_ref()
+                .map_or(Bound::Included(&from), Bound::Excluded);
+            let batch: Vec<Order> = docs.load_range(lower, Bound::Excluded(&to), PICKUP_BATCH)?;
+            let exhausted = batch.len() < PICKUP_BATCH;
+            cursor = batch.last().map(|o| o.meta.id);
+            let found = batch.into_iter().find(|o| {
+                o.pickup_number.as_ref() == Some(&pickup_number)
+                    && o.store.as_ref() == Some(&self.store.id)
+            });
+            if let Some(order) = found {
+                return Ok(OrderStatus::at(order, SystemTime::now()));
+            }
+            if exhausted {
+                return Err(anyhow!("No order {} on {}", pickup_number, day));
+            }
+        }
+    }
+}
+impl<M: r2d2::ManageConnection<Connection = D>, D: Storage + Send + 'static> Queryable<QueryOrders>
+    for Orders<M>
+{
+    fn query(&self, query: QueryOrders) -> Result<OrderPage> {
+        if query.limit == 0 {
+            return Err(anyhow!("Page size must be positive"));
+        }
+        // Ids start with the time they were generated, so the least id at a
+        // given time bounds the orders placed around it.
+        let from = match query.from {
+            Some(t) => Some(UntypedId::at(t, 0)?.typed::<Order>()),
+            None => None,
+        };
+        let to = match query.to {
+            Some(t) => Some(UntypedId::at(t, 0)?.typed::<Order>()),
+            None => None,
+        };
+        let upper = to.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+
+        let docs = self.db.get()?;
+        let now = SystemTime::now();
+        let mut orders = Vec::new();
+        let mut cursor = query.after;
+        loop {
+            let lower = match (cursor.as_ref(), from.as_ref()) {
+                (Some(cursor), Some(from)) if from > cursor => Bound::Included(from),
+                (Some(cursor), _) => Bound::Excluded(cursor),
+                (None, Some(from)) => Bound::Included(from),
+                (None, None) => Bound::Unbounded,
+            };
+            let batch: Vec<Order> = docs.load_range(lower, upper, query.limit)?;
+            let exhausted = batch.len() < query.limit;
+
+            for order in batch {
+                cursor = Some(order.meta.id);
+                let state_matches = query.state.iter().all(|&s| order.state == s);
+                let drink_matches = query
+                    .drink
+                    .iter()
+                    .all(|&d| order.lines.iter().any(|l| l.drink_id == d));
+                if state_matches && drink_matches {
+                    orders.push(OrderStatus::at(order, now));
+                    if orders.len() == query.limit {
+                        let next = cursor;
+                        return Ok(OrderPage { orders, next });
+                    }
+                }
+            }
+
+            if exhausted {
+                let next = None;
+                return Ok(OrderPage { orders, next });
+            }
+        }
+    }
+}
+
+impl OrderStatus {
+    /// The order's status as of `now`, eg: whether it's overdue.
+    fn at(order: Order, now: SystemTime) -> Self {
+        let order_id = order.meta.id;
+        let is_made = order.is_made();
+        let overdue = order.is_overdue(now);
+        let Order {
+            lines,
+            total,
+            discount,
+            customer_id,
+            state,
+            history,
+            ready_by,
+            pickup_number,
+            ..
+        } = order;
+
+        OrderStatus {
+            order_id,
+            pickup_number,
+            is_made,
+            lines,
+            total,
+            discount,
+            customer_id,
+            state,
+            history,
+            ready_by,
+            overdue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::barista::{Barista, BaristaWorker, DrinkPreparation, FailDrink};
+    use crate::customers::Customers;
+    use crate::menu::{Menu, ShowMenu};
+    use crate::payments::{FakeProvider, Payment, PaymentState, PaymentWorker, Payments};
+    use crate::staff::LogAlerts;
+    use crate::testing::MemStore;
+    use infra::documents::{HasMeta, MailBox};
+    use infra::ids::{Entity, StepClock, ThreadRandom};
+    use serde::{de::DeserializeOwned, Serialize};
+
+    fn minutes(n: u64) -> SystemTime {
+        // 2020-05-01T12:00:00Z
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_588_334_400 + n * 60)
+    }
+
+    fn orders(store: &MemStore) -> Orders<MemStore> {
+        Orders::new(store.pool(), IdGen::new(), StoreConfig::default()).expect("orders")
+    }
+
+    fn save_order(store: &MemStore, at: SystemTime, drink_id: Id<Drink>) -> Id<Order> {
+        let id = UntypedId::at(at, 0).expect("in range").typed();
+        let lines = vec![OrderLine::new(drink_id, 1)];
+        let mut order = Order::for_lines(lines, None, id, at);
+        store.save(&mut order).expect("save");
+        id
+    }
+
+    fn cancel(store: &MemStore, order_id: Id<Order>) {
+        let mut order: Order = store.load(&order_id).expect("load").expect("order");
+        order.cancel(SystemTime::now()).expect("cancel");
+        store.save(&mut order).expect("save");
+    }
+
+    fn query(from: Option<SystemTime>, after: Option<Id<Order>>, limit: usize) -> QueryOrders {
+        QueryOrders {
+            from,
+            to: None,
+            state: None,
+            drink: None,
+            after,
+            limit,
+        }
+    }
+
+    fn ids(page: &OrderPage) -> Vec<Id<Order>> {
+        page.orders.iter().map(|o| o.order_id).collect()
+    }
+
+    #[test]
+    fn query_orders_should_page_through_filtered_orders() -> Result<()> {
+        let store = MemStore::default();
+        let idgen = IdGen::new();
+        let placed = (0..5)
+            .map(|n| save_order(&store, minutes(n), idgen.generate()))
+            .collect::<Vec<_>>();
+        cancel(&store, placed[1]);
+        cancel(&store, placed[3]);
+        let cancelled = QueryOrders {
+            state: Some(OrderState::Cancelled),
+            ..query(None, None, 1)
+        };
+
+        // Each page has to skip past orders that don't match.
+        let first = orders(&store).query(cancelled.clone())?;
+        assert_eq!(ids(&first), vec![placed[1]]);
+        assert_eq!(first.next, Some(placed[1]));
+
+        let second = orders(&store).query(QueryOrders {
+            after: first.next,
+            ..cancelled.clone()
+        })?;
+        assert_eq!(ids(&second), vec![placed[3]]);
+        assert_eq!(second.next, Some(placed[3]));
+
+        let last = orders(&store).query(QueryOrders {
+            after: second.next,
+            ..cancelled
+        })?;
+        assert_eq!(ids(&last), vec![]);
+        assert_eq!(last.next, None);
+        Ok(())
+    }
+
+    #[test]
+    fn query_orders_should_filter_by_drink_and_time() -> Result<()> {
+        let store = MemStore::default();
+        let idgen = IdGen::new();
+        let latte = idgen.generate();
+        let placed = (0..4)
+            .map(|n| {
+                let drink = if n % 2 == 0 { latte } else { idgen.generate() };
+                save_order(&store, minutes(n), drink)
+            })
+            .collect::<Vec<_>>();
+
+        let page = orders(&store).query(QueryOrders {
+            drink: Some(latte),
+            to: Some(minutes(2)),
+            ..query(Some(minutes(0)), None, 10)
+        })?;
+
+        assert_eq!(ids(&page), vec![placed[0]]);
+        assert_eq!(page.next, None);
+        Ok(())
+    }
+
+    #[test]
+    fn query_orders_should_start_from_the_later_of_from_and_after() -> Result<()> {
+        let store = MemStore::default();
+        let idgen = IdGen::new();
+        let placed = (0..4)
+            .map(|n| save_order(&store, minutes(n), idgen.generate()))
+            .collect::<Vec<_>>();
+
+        let from_later = orders(&store).query(query(Some(minutes(2)), Some(placed[0]), 10))?;
+        assert_eq!(ids(&from_later), vec![placed[2], placed[3]]);
+
+        let after_later = orders(&store).query(query(Some(minutes(0)), Some(placed[2]), 10))?;
+        assert_eq!(ids(&after_later), vec![placed[3]]);
+        Ok(())
+    }
+
+    fn take_all<A: Eq + std::hash::Hash>(mbox: &mut MailBox<A>) -> Vec<A> {
+        std::iter::from_fn(|| mbox.take_one()).collect()
+    }
+
+    #[test]
+    fn failed_drink_should_cancel_order_and_release_payment() -> Result<()> {
+        let store = MemStore::default();
+        let drink_id = IdGen::new().generate();
+        let order_id = save_order(&store, minutes(0), drink_id);
+        let mut order: Order = store.load(&order_id)?.expect("order");
+        order.payment_authorized(minutes(1))?;
+        store.save(&mut order)?;
+
+        let barista = Barista::new(store.pool())?;
+        let line = 0;
+        barista.execute(PrepareDrink {
+            drink_id,
+            order_id,
+            line,
+            quantity: 1,
+            modifiers: Vec::new(),
+        })?;
+        barista.execute(FailDrink {
+            drink_id,
+            order_id,
+            line,
+        })?;
+        let prep_id = DrinkPreparation::id_for(order_id, drink_id, line);
+        let mut prep: DrinkPreparation = store.load(&prep_id)?.expect("preparation");
+        BaristaWorker::new(store.pool(), orders(&store))?.handle(&mut prep)?;
+
+        let mut order: Order = store.load(&order_id)?.expect("order");
+        assert_eq!(order.state, OrderState::Cancelled);
+        let sent = take_all(&mut order.mbox);
+        assert!(
+            sent.contains(&OrderMsg::ReleasePayment { order_id }),
+            "Sent: {:?}",
+            sent
+        );
+        assert!(
+            sent.contains(&OrderMsg::CancelPreparation {
+                drink_id,
+                order_id,
+                line
+            }),
+            "Sent: {:?}",
+            sent
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overdue_should_only_consider_orders_within_lookback() -> Result<()> {
+        let store = MemStore::default();
+        let placed = minutes(0);
+        let order_id = save_order(&store, placed, IdGen::new().generate());
+        let mut order: Order = store.load(&order_id)?.expect("order");
+        order.ready_by = Some(placed + READY_WITHIN);
+        store.save(&mut order)?;
+        let now = placed + Duration::from_secs(2 * 24 * 60 * 60);
+
+        let overdue = orders(&store).query(QueryOverdue { now })?;
+        assert_eq!(overdue, vec![]);
+
+        let longer = StoreConfig {
+            overdue_lookback: Duration::from_secs(3 * 24 * 60 * 60),
+            ..StoreConfig::default()
+        };
+        let orders = Orders::new(store.pool(), IdGen::new(), longer)?;
+        let overdue = orders.query(QueryOverdue { now })?;
+        assert_eq!(
+            overdue.iter().map(|o| o.order_id).collect::<Vec<_>>(),
+            vec![order_id]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overdue_statuses_should_be_as_of_the_given_time() -> Result<()> {
+        let store = MemStore::default();
+        // Long after anyone runs this test.
+        let placed = SystemTime::UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+        let order_id = save_order(&store, placed, IdGen::new().generate());
+        let mut order: Order = store.load(&order_id)?.expect("order");
+        order.ready_by = Some(placed + READY_WITHIN);
+        store.save(&mut order)?;
+
+        let now = placed + READY_WITHIN * 2;
+        let overdue = orders(&store).query(QueryOverdue { now })?;
+
+        assert_eq!(overdue.len(), 1);
+        assert!(overdue[0].overdue, "Status: {:?}", overdue[0]);
+        Ok(())
+    }
+
+    fn umbrella(store: &MemStore) -> Result<Id<Drink>> {
+        let menu = Menu::new(store.pool(), IdGen::new())?;
+        menu.setup()?;
+        let drinks = menu.query(ShowMenu)?;
+        Ok(drinks
+            .iter()
+            .find(|d| d.name == "Umbrella")
+            .map(|d| d.meta().id)
+            .expect("umbrella"))
+    }
+
+    fn place_order(orders: &Orders<MemStore>, drink_id: Id<Drink>) -> Result<OrderPlaced> {
+        orders.execute(PlaceOrder {
+            lines: vec![LineItem {
+                drink_id,
+                quantity: 1,
+                modifiers: Vec::new(),
+            }],
+            customer_id: None,
+            redeem_points: 0,
+        })
+    }
+
+    #[test]
+    fn place_order_should_reject_too_many_drinks() -> Result<()> {
+        let store = MemStore::default();
+        let drink_id = umbrella(&store)?;
+        let line = LineItem {
+            drink_id,
+            quantity: u32::MAX,
+            modifiers: Vec::new(),
+        };
+
+        let err = orders(&store)
+            .execute(PlaceOrder {
+                lines: vec![line.clone(), line],
+                customer_id: None,
+                redeem_points: 0,
+            })
+            .expect_err("too many drinks");
+
+        assert_eq!(
+            err.downcast_ref::<OrderError>(),
+            Some(&OrderError::TooManyDrinks)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn next_pickup_should_retry_when_the_counter_changes() -> Result<()> {
+        let store = MemStore::default();
+        let orders = orders(&store);
+        let docs = store.pool().get()?;
+
+        store.conflict_next(PICKUP_ATTEMPTS - 1);
+        assert_eq!(
+            orders.next_pickup(&docs, minutes(0))?,
+            PickupNumber::new("A", 1)
+        );
+
+        store.conflict_next(PICKUP_ATTEMPTS);
+        assert!(orders.next_pickup(&docs, minutes(0)).is_err());
+        assert_eq!(
+            orders.next_pickup(&docs, minutes(0))?,
+            PickupNumber::new("A", 2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_pickup_should_find_orders_on_the_day_of_their_id() -> Result<()> {
+        let store = MemStore::default();
+        let drink_id = umbrella(&store)?;
+        // The first order's id falls just before midnight; the second's just
+        // after.
+        let midnight = minutes(12 * 60);
+        let clock = StepClock::new(
+            midnight - Duration::from_millis(1),
+            Duration::from_millis(1),
+        );
+        let config = StoreConfig {
+            pickup_prefix: "b".to_string(),
+            ..StoreConfig::default()
+        };
+        let orders = Orders::new(
+            store.pool(),
+            IdGen::with_sources(clock, ThreadRandom),
+            config,
+        )?;
+
+        let late = place_order(&orders, drink_id)?;
+        let early = place_order(&orders, drink_id)?;
+        assert_eq!(late.pickup_number, PickupNumber::new("B", 1));
+        assert_eq!(early.pickup_number, PickupNumber::new("B", 1));
+
+        let pickup_number: PickupNumber = "b001".parse()?;
+        let on_late_day = orders.query(QueryPickup {
+            pickup_number: pickup_number.clone(),
+            on: midnight - Duration::from_secs(60),
+        })?;
+        let on_early_day = orders.query(QueryPickup {
+            pickup_number: pickup_number.clone(),
+            on: midnight,
+        })?;
+        assert_eq!(on_late_day.order_id, late.order_id);
+        assert_eq!(on_early_day.order_id, early.order_id);
+
+        assert!(orders
+            .query(QueryPickup {
+                pickup_number,
+                on: midnight + Duration::from_secs(24 * 60 * 60),
+            })
+            .is_err());
+        Ok(())
+    }
+
+    /// Hands any messages waiting in the document to the worker, as
+    /// `subscribe` would.
+    fn deliver<T, F>(store: &MemStore, id: Id<T>, handle: F) -> Result<()>
+    where
+        T: Entity + HasMeta + Serialize + DeserializeOwned,
+        F: Fn(&mut T) -> Result<()>,
+    {
+        if let Some(mut doc) = store.load(&id)? {
+            handle(&mut doc)?;
+            store.save(&mut doc)?;
+        }
+        Ok(())
+    }
+
+    /// Places and makes an order, returning what should only depend on the
+    /// seed.
+    fn full_flow(seed: u64) -> Result<(Id<Order>, PickupNumber, Option<String>)> {
+        let store = MemStore::default();
+        let drink_id = umbrella(&store)?;
+        let orders = Orders::new(
+            store.pool(),
+            IdGen::deterministic(seed),
+            StoreConfig::default(),
+        )?;
+        let order_worker = OrderWorker::new(
+            store.pool(),
+            Barista::new(store.pool())?,
+            Payments::new(store.pool(), FakeProvider::default())?,
+            Customers::new(store.pool(), IdGen::new())?,
+            LogAlerts,
+        )?;
+        let payment_worker = PaymentWorker::new(store.pool(), self::orders(&store))?;
+        let barista_worker = BaristaWorker::new(store.pool(), self::orders(&store))?;
+
+        let placed = place_order(&orders, drink_id)?;
+        let order_id = placed.order_id;
+        let prep_id = DrinkPreparation::id_for(order_id, drink_id, 0);
+        for _ in 0..4 {
+            deliver(&store, order_id, |o| order_worker.handle(o))?;
+            deliver(&store, Payment::id_for(order_id), |p| {
+                payment_worker.handle(p)
+            })?;
+            deliver(&store, prep_id, |p| barista_worker.handle(p))?;
+        }
+
+        let order: Order = store.load(&order_id)?.expect("order");
+        assert_eq!(order.state, OrderState::Ready);
+        let payment: Payment = store.load(&Payment::id_for(order_id))?.expect("payment");
+        assert_eq!(payment.state, PaymentState::Captured);
+        Ok((order_id, placed.pickup_number, payment.authorization))
+    }
+
+    #[test]
+    fn deterministic_idgen_should_reproduce_the_full_flow() -> Result<()> {
+        let first = full_flow(7)?;
+
+        assert_eq!(first, full_flow(7)?);
+        assert_ne!(first.0, full_flow(8)?.0);
+        Ok(())
+    }
 
-        let resp = OrderStatus { order_id, is_made };
+    #[test]
+    fn query_orders_should_reject_times_before_1970() {
+        let store = MemStore::default();
+        let before = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
 
-        Ok(resp)
+        assert!(orders(&store).query(query(Some(before), None, 10)).is_err());
     }
 }
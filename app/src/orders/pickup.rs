@@ -0,0 +1,128 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use infra::documents::{DocMeta, HasMeta};
+use infra::ids::{Entity, Id, IdGen};
+
+/// A short number to call an order by, eg: `A042`; unique within a store's
+/// day.
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PickupNumber {
+    pub prefix: String,
+    pub number: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, err_derive::Error)]
+pub enum PickupError {
+    #[error(display = "Pickup numbers look like A042, not {:?}", _0)]
+    Unparseable(String),
+}
+
+/// Hands out the pickup numbers for one store on one (UTC) day.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PickupCounter {
+    #[serde(flatten)]
+    pub(super) meta: DocMeta<PickupCounter>,
+    pub(super) store: String,
+    pub(super) day: String,
+    pub(super) last: u32,
+}
+
+impl PickupNumber {
+    /// Prefixes are upper-cased, as they are when parsed.
+    pub fn new(prefix: &str, number: u32) -> Self {
+        let prefix = prefix.to_uppercase();
+        PickupNumber { prefix, number }
+    }
+}
+
+impl fmt::Display for PickupNumber {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}{:03}", self.prefix, self.number)
+    }
+}
+
+impl FromStr for PickupNumber {
+    type Err = PickupError;
+    /// Accepts the prefix in any case, eg: `a42` for `A042`.
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let digits = src.find(|c: char| c.is_ascii_digit());
+        match digits {
+            Some(idx) if idx > 0 && src[..idx].chars().all(|c| c.is_ascii_alphabetic()) => {
+                let number = src[idx..]
+                    .parse()
+                    .map_err(|_| PickupError::Unparseable(src.to_string()))?;
+                Ok(PickupNumber::new(&src[..idx], number))
+            }
+            _ => Err(PickupError::Unparseable(src.to_string())),
+        }
+    }
+}
+
+impl PickupCounter {
+    pub(super) fn new(idgen: &IdGen, store: &str, day: &str) -> Self {
+        let meta = DocMeta::new_with_id(Self::id_for(idgen, store, day));
+        let store = store.to_string();
+        let day = day.to_string();
+        PickupCounter {
+            meta,
+            store,
+            day,
+            last: 0,
+        }
+    }
+
+    pub(super) fn id_for(idgen: &IdGen, store: &str, day: &str) -> Id<PickupCounter> {
+        idgen.hashed((store, day))
+    }
+
+    pub(super) fn next(&mut self) -> u32 {
+        self.last += 1;
+        self.last
+    }
+}
+
+impl Entity for PickupCounter {
+    const PREFIX: &'static str = "pickup-counter";
+}
+
+impl HasMeta for PickupCounter {
+    fn meta(&self) -> &DocMeta<Self> {
+        &self.meta
+    }
+    fn meta_mut(&mut self) -> &mut DocMeta<Self> {
+        &mut self.meta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_display_padded_numbers() {
+        assert_eq!(PickupNumber::new("A", 42).to_string(), "A042");
+        assert_eq!(PickupNumber::new("A", 1042).to_string(), "A1042");
+    }
+
+    #[test]
+    fn should_parse_pickup_numbers() {
+        assert_eq!("A042".parse(), Ok(PickupNumber::new("A", 42)));
+        assert_eq!("a42".parse(), Ok(PickupNumber::new("A", 42)));
+        assert_eq!("A042".parse(), Ok(PickupNumber::new("a", 42)));
+        assert!("042".parse::<PickupNumber>().is_err());
+        assert!("A".parse::<PickupNumber>().is_err());
+        assert!("A4x".parse::<PickupNumber>().is_err());
+    }
+
+    #[test]
+    fn counters_should_be_per_store_and_day() {
+        let idgen = IdGen::new();
+        let id = PickupCounter::id_for(&idgen, "main", "2020-05-01");
+        assert_eq!(id, PickupCounter::id_for(&idgen, "main", "2020-05-01"));
+        assert_ne!(id, PickupCounter::id_for(&idgen, "main", "2020-05-02"));
+        assert_ne!(id, PickupCounter::id_for(&idgen, "kiosk", "2020-05-01"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::*;
+use r2d2::Pool;
+
+use infra::persistence::{OutboxStatus, StorageStatus};
+
+use crate::services::{Queryable, Request};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryOutbox;
+
+/// Receives measurements about the outbox backlog, eg: to forward them to
+/// an alerting system.
+pub trait MetricsSink {
+    fn gauge(&self, name: &str, prefix: &str, value: f64);
+}
+
+/// Writes each measurement to the log under the `metrics` target.
+#[derive(Debug, Clone, Default)]
+pub struct LogMetrics;
+
+#[derive(Debug)]
+pub struct Outbox<M: r2d2::ManageConnection> {
+    db: Pool<M>,
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: StorageStatus + Send + 'static> Outbox<M> {
+    pub fn new(db: Pool<M>) -> Result<Self> {
+        Ok(Outbox { db })
+    }
+
+    pub fn publish<S: MetricsSink>(&self, sink: &S) -> Result<Vec<OutboxStatus>> {
+        let statuses = self.query(QueryOutbox)?;
+        for status in statuses.iter() {
+            sink.gauge(
+                "outbox_pending_documents",
+                &status.prefix,
+                status.pending as f64,
+            );
+            let age = status
+                .oldest_pending_age
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default();
+            sink.gauge("outbox_oldest_pending_seconds", &status.prefix, age);
+        }
+        Ok(statuses)
+    }
+
+    pub fn publish_every<S: MetricsSink>(&self, sink: &S, interval: Duration) -> Result<()> {
+        loop {
+            self.publish(sink)?;
+            thread::sleep(interval);
+        }
+    }
+}
+
+impl MetricsSink for LogMetrics {
+    fn gauge(&self, name: &str, prefix: &str, value: f64) {
+        info!(target: "metrics", "{}{{prefix={:?}}} {}", name, prefix, value);
+    }
+}
+
+impl Request for QueryOutbox {
+    type Resp = Vec<OutboxStatus>;
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: StorageStatus + Send + 'static>
+    Queryable<QueryOutbox> for Outbox<M>
+{
+    fn query(&self, _query: QueryOutbox) -> Result<Vec<OutboxStatus>> {
+        let conn = self.db.get()?;
+        conn.outbox_status()
+    }
+}
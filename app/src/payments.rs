@@ -0,0 +1,473 @@
+use anyhow::Result;
+use log::*;
+use r2d2::{self, Pool};
+use serde::{Deserialize, Serialize};
+
+use infra::{
+    documents::{DocMeta, HasMeta, MailBox},
+    ids::{Entity, Id},
+    persistence::{Storage, StoragePending},
+};
+
+use crate::money::Money;
+use crate::orders::{Order, PaymentAuthorized, PaymentDeclined};
+use crate::services::{Commandable, Request};
+
+/// Takes payment for an order from some external party, eg: a card
+/// processor.
+pub trait PaymentProvider {
+    /// The amount is `None` for orders placed before drinks had prices.
+    /// Authorizing again with the same reference returns the same
+    /// authorization, rather than placing another hold.
+    fn authorize(&self, reference: &str, amount: Option<&Money>) -> Result<Authorization>;
+    fn capture(&self, authorization: &str) -> Result<()>;
+    fn void(&self, authorization: &str) -> Result<()>;
+    fn refund(&self, authorization: &str) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    Approved(String),
+    Declined,
+}
+
+/// Approves (or declines) everything, and moves no money.
+#[derive(Debug, Clone, Default)]
+pub struct FakeProvider {
+    decline: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AuthorizePayment {
+    pub order_id: Id<Order>,
+    pub amount: Option<Money>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CapturePayment {
+    pub order_id: Id<Order>,
+}
+
+/// Voids the payment for an order, or refunds it if it has been captured.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReleasePayment {
+    pub order_id: Id<Order>,
+}
+
+#[derive(Debug)]
+pub struct Payments<M: r2d2::ManageConnection, P> {
+    db: Pool<M>,
+    provider: P,
+}
+
+#[derive(Debug)]
+pub struct PaymentWorker<M: r2d2::ManageConnection, O> {
+    db: Pool<M>,
+    orders: O,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    #[serde(flatten)]
+    pub(super) meta: DocMeta<Payment>,
+    #[serde(flatten)]
+    pub(super) mbox: MailBox<PaymentMsg>,
+    pub(super) order_id: Id<Order>,
+    #[serde(default)]
+    pub(super) amount: Option<Money>,
+    pub(super) state: PaymentState,
+    pub(super) authorization: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaymentState {
+    Pending,
+    /// We may have asked the provider for authorization, but have yet to
+    /// record the answer.
+    Authorizing,
+    Authorized,
+    Declined,
+    Captured,
+    Voided,
+    Refunded,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub(super) enum PaymentMsg {
+    Authorized { order_id: Id<Order> },
+    Declined { order_id: Id<Order> },
+}
+
+impl FakeProvider {
+    pub fn declining() -> Self {
+        FakeProvider { decline: true }
+    }
+}
+
+impl PaymentProvider for FakeProvider {
+    fn authorize(&self, reference: &str, amount: Option<&Money>) -> Result<Authorization> {
+        if self.decline {
+            info!("Declining payment: {}; amount:{:?}", reference, amount);
+            return Ok(Authorization::Declined);
+        }
+        info!("Authorizing payment: {}; amount:{:?}", reference, amount);
+        Ok(Authorization::Approved(format!("fake-{}", reference)))
+    }
+    fn capture(&self, authorization: &str) -> Result<()> {
+        info!("Capturing payment: {}", authorization);
+        Ok(())
+    }
+    fn void(&self, authorization: &str) -> Result<()> {
+        info!("Voiding payment: {}", authorization);
+        Ok(())
+    }
+    fn refund(&self, authorization: &str) -> Result<()> {
+        info!("Refunding payment: {}", authorization);
+        Ok(())
+    }
+}
+
+impl Payment {
+    fn for_order(order_id: Id<Order>) -> Self {
+        let meta = DocMeta::new_with_id(Self::id_for(order_id));
+        let mbox = MailBox::empty();
+        let amount = None;
+        let state = PaymentState::Pending;
+        let authorization = None;
+        Payment {
+            meta,
+            mbox,
+            order_id,
+            amount,
+            state,
+            authorization,
+        }
+    }
+
+    pub(crate) fn id_for(order_id: Id<Order>) -> Id<Payment> {
+        order_id.derive(())
+    }
+
+    /// Records that we're about to ask for authorization, so that anyone
+    /// releasing the payment from then on knows to look for a hold. Returns
+    /// whether the payment needs saving before we ask.
+    pub(crate) fn start_authorizing(&mut self, amount: Option<Money>) -> bool {
+        if self.state != PaymentState::Pending {
+            return false;
+        }
+        self.amount = amount;
+        self.state = PaymentState::Authorizing;
+        true
+    }
+
+    pub(crate) fn authorize<P: PaymentProvider>(&mut self, provider: &P) -> Result<()> {
+        if self.state != PaymentState::Authorizing {
+            debug!("Payment {} already {:?}", self.meta.id, self.state);
+            return Ok(());
+        }
+
+        let order_id = self.order_id;
+        match provider.authorize(&self.meta.id.to_string(), self.amount.as_ref())? {
+            Authorization::Approved(authorization) => {
+                self.state = PaymentState::Authorized;
+                self.authorization = Some(authorization);
+                self.mbox.send(PaymentMsg::Authorized { order_id });
+            }
+            Authorization::Declined => {
+                self.state = PaymentState::Declined;
+                self.mbox.send(PaymentMsg::Declined { order_id });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn capture<P: PaymentProvider>(&mut self, provider: &P) -> Result<()> {
+        match (self.state, &self.authorization) {
+            (PaymentState::Authorized, Some(authorization)) => {
+                provider.capture(authorization)?;
+                self.state = PaymentState::Captured;
+            }
+            (state, _) => warn!(
+                "Not capturing payment {} in state {:?}",
+                self.meta.id, state
+            ),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn release<P: PaymentProvider>(&mut self, provider: &P) -> Result<()> {
+        match (self.state, &self.authorization) {
+            // Released before we asked for it; make sure we never do.
+            (PaymentState::Pending, _) => self.state = PaymentState::Voided,
+            // Asking again finds any hold we placed, without placing another.
+            (PaymentState::Authorizing, _) => {
+                let reference = self.meta.id.to_string();
+                if let Authorization::Approved(authorization) =
+                    provider.authorize(&reference, self.amount.as_ref())?
+                {
+                    provider.void(&authorization)?;
+                    self.authorization = Some(authorization);
+                }
+                self.state = PaymentState::Voided;
+            }
+            (PaymentState::Authorized, Some(authorization)) => {
+                provider.void(authorization)?;
+                self.state = PaymentState::Voided;
+            }
+            (PaymentState::Captured, Some(authorization)) => {
+                provider.refund(authorization)?;
+                self.state = PaymentState::Refunded;
+            }
+            (state, _) => debug!("Nothing to release for {} in {:?}", self.meta.id, state),
+        }
+        Ok(())
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + Send + 'static,
+        P: PaymentProvider,
+    > Payments<M, P>
+{
+    pub fn new(db: Pool<M>, provider: P) -> Result<Self> {
+        Ok(Payments { db, provider })
+    }
+
+    fn load_or_create(&self, order_id: Id<Order>) -> Result<Payment> {
+        let payment = self
+            .db
+            .load(&Payment::id_for(order_id))?
+            .unwrap_or_else(|| Payment::for_order(order_id));
+        Ok(payment)
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + StoragePending + Send + 'static,
+        O: Commandable<PaymentAuthorized> + Commandable<PaymentDeclined>,
+    > PaymentWorker<M, O>
+{
+    pub fn process_action(&self) -> Result<()> {
+        self.db
+            .get()?
+            .subscribe(|doc: &mut Payment| self.handle(doc))?;
+        Ok(())
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection,
+        O: Commandable<PaymentAuthorized> + Commandable<PaymentDeclined>,
+    > PaymentWorker<M, O>
+{
+    pub fn new(db: Pool<M>, orders: O) -> Result<Self> {
+        Ok(PaymentWorker { db, orders })
+    }
+
+    pub fn handle(&self, doc: &mut Payment) -> Result<()> {
+        info!("Found pending document: {:?}", doc);
+        while let Some(act) = doc.mbox.take_one() {
+            self.handle_payment_action(act)?;
+        }
+        Ok(())
+    }
+
+    fn handle_payment_action(&self, action: PaymentMsg) -> Result<()> {
+        info!("Action: {:?}", action);
+        match action {
+            PaymentMsg::Authorized { order_id } => {
+                self.orders.execute(PaymentAuthorized { order_id })?
+            }
+            PaymentMsg::Declined { order_id } => {
+                self.orders.execute(PaymentDeclined { order_id })?
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Request for AuthorizePayment {
+    type Resp = ();
+}
+
+impl Request for CapturePayment {
+    type Resp = ();
+}
+
+impl Request for ReleasePayment {
+    type Resp = ();
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + Send + 'static,
+        P: PaymentProvider,
+    > Commandable<AuthorizePayment> for Payments<M, P>
+{
+    fn execute(&self, AuthorizePayment { order_id, amount }: AuthorizePayment) -> Result<()> {
+        let docs = self.db.get()?;
+        let mut payment = self.load_or_create(order_id)?;
+        if payment.start_authorizing(amount) {
+            docs.save(&mut payment)?;
+        }
+        payment.authorize(&self.provider)?;
+        docs.save(&mut payment)?;
+        debug!("Saved {:?}", payment);
+        Ok(())
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + Send + 'static,
+        P: PaymentProvider,
+    > Commandable<CapturePayment> for Payments<M, P>
+{
+    fn execute(&self, CapturePayment { order_id }: CapturePayment) -> Result<()> {
+        let mut payment = self.load_or_create(order_id)?;
+        payment.capture(&self.provider)?;
+        self.db.get()?.save(&mut payment)?;
+        debug!("Saved {:?}", payment);
+        Ok(())
+    }
+}
+
+impl<
+        M: r2d2::ManageConnection<Connection = D>,
+        D: Storage + Send + 'static,
+        P: PaymentProvider,
+    > Commandable<ReleasePayment> for Payments<M, P>
+{
+    fn execute(&self, ReleasePayment { order_id }: ReleasePayment) -> Result<()> {
+        let mut payment = self.load_or_create(order_id)?;
+        payment.release(&self.provider)?;
+        self.db.get()?.save(&mut payment)?;
+        debug!("Saved {:?}", payment);
+        Ok(())
+    }
+}
+
+impl Entity for Payment {
+    const PREFIX: &'static str = "payment";
+}
+
+impl HasMeta for Payment {
+    fn meta(&self) -> &DocMeta<Self> {
+        &self.meta
+    }
+    fn meta_mut(&mut self) -> &mut DocMeta<Self> {
+        &mut self.meta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+
+    use crate::testing::MemStore;
+    use infra::ids::IdGen;
+
+    /// Keeps track of the holds it has placed on the customer's card, placing
+    /// at most one per reference.
+    #[derive(Debug, Default)]
+    struct HoldingProvider {
+        references: RefCell<BTreeSet<String>>,
+        holds: RefCell<BTreeSet<String>>,
+    }
+
+    impl PaymentProvider for HoldingProvider {
+        fn authorize(&self, reference: &str, _: Option<&Money>) -> Result<Authorization> {
+            let authorization = format!("hold-{}", reference);
+            if self.references.borrow_mut().insert(reference.to_string()) {
+                self.holds.borrow_mut().insert(authorization.clone());
+            }
+            Ok(Authorization::Approved(authorization))
+        }
+        fn capture(&self, authorization: &str) -> Result<()> {
+            self.holds.borrow_mut().remove(authorization);
+            Ok(())
+        }
+        fn void(&self, authorization: &str) -> Result<()> {
+            self.holds.borrow_mut().remove(authorization);
+            Ok(())
+        }
+        fn refund(&self, _: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_capture_authorized_payment() -> Result<()> {
+        let provider = FakeProvider::default();
+        let order_id = IdGen::new().generate();
+        let mut payment = Payment::for_order(order_id);
+
+        assert!(payment.start_authorizing(None));
+        payment.authorize(&provider)?;
+        assert_eq!(payment.state, PaymentState::Authorized);
+        assert_eq!(
+            payment.mbox.take_one(),
+            Some(PaymentMsg::Authorized { order_id })
+        );
+
+        payment.capture(&provider)?;
+        assert_eq!(payment.state, PaymentState::Captured);
+        payment.release(&provider)?;
+        assert_eq!(payment.state, PaymentState::Refunded);
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_declined_payment() -> Result<()> {
+        let order_id = IdGen::new().generate();
+        let mut payment = Payment::for_order(order_id);
+
+        payment.start_authorizing(None);
+        payment.authorize(&FakeProvider::declining())?;
+
+        assert_eq!(payment.state, PaymentState::Declined);
+        assert_eq!(
+            payment.mbox.take_one(),
+            Some(PaymentMsg::Declined { order_id })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_never_authorize_once_released() -> Result<()> {
+        let provider = FakeProvider::default();
+        let mut payment = Payment::for_order(IdGen::new().generate());
+
+        payment.release(&provider)?;
+        assert!(!payment.start_authorizing(None));
+        payment.authorize(&provider)?;
+
+        assert_eq!(payment.state, PaymentState::Voided);
+        assert_eq!(payment.mbox.take_one(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn release_while_authorizing_should_void_any_hold() -> Result<()> {
+        let store = MemStore::default();
+        let provider = HoldingProvider::default();
+        let mut authorizing = Payment::for_order(IdGen::new().generate());
+        authorizing.start_authorizing(Some(Money::new("GBP", 320)));
+        store.save(&mut authorizing)?;
+
+        // The order is cancelled while we wait on the provider.
+        let mut releasing: Payment = store.load(&authorizing.meta.id)?.expect("payment");
+        releasing.release(&provider)?;
+        store.save(&mut releasing)?;
+        authorizing.authorize(&provider)?;
+
+        assert!(store.save(&mut authorizing).is_err());
+        assert_eq!(releasing.state, PaymentState::Voided);
+        assert_eq!(*provider.holds.borrow(), BTreeSet::new());
+        Ok(())
+    }
+}
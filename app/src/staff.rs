@@ -0,0 +1,31 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::*;
+
+use crate::orders::Order;
+use crate::services::{Commandable, Request};
+use infra::ids::Id;
+
+/// Asks for a person to look at an order that's running late.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AlertStaff {
+    pub order_id: Id<Order>,
+    pub ready_by: SystemTime,
+}
+
+/// Alerts staff via the log, for whatever's watching it to pass on.
+#[derive(Debug, Clone, Default)]
+pub struct LogAlerts;
+
+impl Request for AlertStaff {
+    type Resp = ();
+}
+
+impl Commandable<AlertStaff> for LogAlerts {
+    fn execute(&self, AlertStaff { order_id, ready_by }: AlertStaff) -> Result<()> {
+        let late = ready_by.elapsed().unwrap_or_default();
+        warn!("Order overdue: {}; late by:{:?}", order_id, late);
+        Ok(())
+    }
+}
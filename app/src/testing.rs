@@ -0,0 +1,111 @@
+//! An in-memory document store, so that services can be tested without a
+//! database.
+
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use serde::{de::DeserializeOwned, Serialize};
+
+use infra::{
+    documents::{HasMeta, Version},
+    ids::{Entity, Id},
+    persistence::{ConcurrencyError, Storage},
+};
+
+/// Every connection from the same store shares its documents.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    docs: HashMap<String, serde_json::Value>,
+    conflicts: usize,
+}
+
+impl MemStore {
+    pub(crate) fn pool(&self) -> r2d2::Pool<MemStore> {
+        r2d2::Pool::builder()
+            .max_size(2)
+            .build(self.clone())
+            .expect("pool")
+    }
+
+    /// Fails the next `n` saves as though someone else saved first.
+    pub(crate) fn conflict_next(&self, n: usize) {
+        self.inner.lock().expect("lock").conflicts = n;
+    }
+}
+
+impl Storage for MemStore {
+    fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
+        let inner = self.inner.lock().expect("lock");
+        inner
+            .docs
+            .get(&id.to_string())
+            .map(|v| Ok(serde_json::from_value(v.clone())?))
+            .transpose()
+    }
+
+    fn load_range<D: DeserializeOwned + Entity>(
+        &self,
+        from: Bound<&Id<D>>,
+        to: Bound<&Id<D>>,
+        limit: usize,
+    ) -> Result<Vec<D>, Error> {
+        let inner = self.inner.lock().expect("lock");
+        let mut ids = inner
+            .docs
+            .keys()
+            .filter_map(|k| k.parse::<Id<D>>().ok())
+            .filter(|id| (from, to).contains(id))
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.into_iter()
+            .take(limit)
+            .map(|id| Ok(serde_json::from_value(inner.docs[&id.to_string()].clone())?))
+            .collect()
+    }
+
+    fn save<D: Serialize + Entity + HasMeta>(&self, document: &mut D) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("lock");
+        let id = document.meta().id.to_string();
+        let expected = serde_json::to_value(&document.meta().version)?;
+        let stored = inner.docs.get(&id).map(|d| d["_version"].clone());
+        let fresh = document.meta().version == Version::default();
+        let stale = if fresh {
+            stored.is_some()
+        } else {
+            stored != Some(expected)
+        };
+        if stale || inner.conflicts > 0 {
+            inner.conflicts = inner.conflicts.saturating_sub(1);
+            return Err(ConcurrencyError.into());
+        }
+
+        document.meta_mut().increment_version();
+        let value = serde_json::to_value(&*document)?;
+        inner.docs.insert(id, value);
+        Ok(())
+    }
+}
+
+impl r2d2::ManageConnection for MemStore {
+    type Connection = MemStore;
+    type Error = std::io::Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        Ok(self.clone())
+    }
+
+    fn is_valid(&self, _: &mut Self::Connection) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut Self::Connection) -> bool {
+        false
+    }
+}
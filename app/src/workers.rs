@@ -0,0 +1,34 @@
+use anyhow::Result;
+use r2d2::Pool;
+
+use infra::persistence::StorageStatus;
+use infra::workers::WorkerStatus;
+
+use crate::services::{Queryable, Request};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryWorkers;
+
+#[derive(Debug)]
+pub struct Workers<M: r2d2::ManageConnection> {
+    db: Pool<M>,
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: StorageStatus + Send + 'static> Workers<M> {
+    pub fn new(db: Pool<M>) -> Result<Self> {
+        Ok(Workers { db })
+    }
+}
+
+impl Request for QueryWorkers {
+    type Resp = Vec<WorkerStatus>;
+}
+
+impl<M: r2d2::ManageConnection<Connection = D>, D: StorageStatus + Send + 'static>
+    Queryable<QueryWorkers> for Workers<M>
+{
+    fn query(&self, _query: QueryWorkers) -> Result<Vec<WorkerStatus>> {
+        let conn = self.db.get()?;
+        conn.workers()
+    }
+}
@@ -64,7 +64,15 @@ impl<A: Hash + Eq> MailBox<A> {
     }
 
     pub fn take_one(&mut self) -> Option<A> {
-        self.outgoing.drain().next()
+        let mut remaining = std::mem::take(&mut self.outgoing).into_iter();
+        let next = remaining.next();
+        self.outgoing = remaining.collect();
+        next
+    }
+
+    /// Withdraws any unsent messages that don't match the predicate.
+    pub fn retain<F: FnMut(&A) -> bool>(&mut self, f: F) {
+        self.outgoing.retain(f)
     }
 }
 
@@ -114,4 +122,38 @@ mod test {
         // ... A miracle has now occurred. Honest.
         assert_eq!(dst.items, 1);
     }
+
+    #[test]
+    fn take_one_should_keep_remaining_messages() {
+        let mut mbox = MailBox::empty();
+        mbox.send(1);
+        mbox.send(2);
+        mbox.send(3);
+
+        let mut taken = Vec::new();
+        while let Some(msg) = mbox.take_one() {
+            taken.push(msg);
+        }
+        taken.sort();
+
+        assert_eq!(taken, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn retain_should_withdraw_unsent_messages() {
+        let mut mbox = MailBox::empty();
+        mbox.send(1);
+        mbox.send(2);
+        mbox.send(3);
+
+        mbox.retain(|&m| m != 2);
+
+        let mut taken = Vec::new();
+        while let Some(msg) = mbox.take_one() {
+            taken.push(msg);
+        }
+        taken.sort();
+
+        assert_eq!(taken, vec![1, 3]);
+    }
 }
@@ -1,15 +1,19 @@
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use anyhow::Error;
-use data_encoding::BASE32_DNSSEC;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::untyped_ids::UntypedId;
+use crate::untyped_ids::{IdBytesVisitor, IdKey, UntypedId};
 
 pub(crate) const ENCODED_BARE_ID_LEN: usize = 26;
+pub(crate) const CHECKED_BARE_ID_LEN: usize = ENCODED_BARE_ID_LEN + 1;
 
 pub struct Id<T> {
     // Unix time in ms
@@ -17,6 +21,14 @@ pub struct Id<T> {
     phantom: PhantomData<T>,
 }
 
+/// An id whose entity type is only known at runtime, eg: one supplied by an
+/// operator.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
+pub struct AnyId {
+    pub prefix: String,
+    pub id: UntypedId,
+}
+
 #[derive(Debug, Clone, err_derive::Error)]
 pub enum IdParseError {
     #[error(display = "Invalid identifier prefix")]
@@ -25,32 +37,144 @@ pub enum IdParseError {
     Unparseable,
     #[error(display = "Invalid encoding: {:?}", _0)]
     Encoding(#[error(no_from)] data_encoding::DecodePartial),
+    #[error(display = "Check symbol does not match; is the identifier mistyped?")]
+    InvalidCheckSymbol,
 }
 
 pub trait Entity {
     const PREFIX: &'static str;
 }
 
+#[derive(Debug, Clone)]
+pub struct IdGen {
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) random: Arc<dyn RandomSource>,
+    // The most recently generated id, shared between clones, when in
+    // monotonic mode.
+    pub(crate) last: Option<Arc<Mutex<UntypedId>>>,
+    key: IdKey,
+}
+
+/// Supplies the timestamp portion of generated ids.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Supplies the random portion of generated ids.
+pub trait RandomSource: fmt::Debug + Send + Sync {
+    fn next_u64(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
 #[derive(Debug, Clone, Default)]
-pub struct IdGen {}
+pub struct ThreadRandom;
+
+/// A clock that starts at a fixed time, and advances by a fixed step each
+/// time it is read. Panics rather than wrap once the time can't be
+/// represented.
+#[derive(Debug)]
+pub struct StepClock {
+    start: SystemTime,
+    step: Duration,
+    ticks: AtomicU64,
+}
+
+/// A seeded SplitMix64 generator, so the same seed always yields the same
+/// sequence.
+#[derive(Debug)]
+pub struct SeededRandom {
+    state: AtomicU64,
+}
 
 const DIVIDER: &str = ".";
 
-impl<T> Id<T> {
+impl<T: Entity> Id<T> {
     /// Returns a id nominally at time zero, but with a random portion derived
-    /// from the given entity.
-    pub fn hashed<H: Hash>(entity: H) -> Self {
+    /// from the given entity and the entity type's prefix. Unkeyed, so only
+    /// for tests; deployments hash with `IdGen::hashed`, which uses their key.
+    #[cfg(test)]
+    pub(crate) fn hashed<H: Hash>(entity: H) -> Self {
+        Self::hashed_with(&IdKey::default(), entity)
+    }
+
+    /// As `hashed`, but also keyed, eg: by a deployment secret.
+    pub fn hashed_with<H: Hash>(key: &IdKey, entity: H) -> Self {
+        let inner = UntypedId::hashed_with(key, (T::PREFIX, entity));
+        let phantom = PhantomData;
+        Id { inner, phantom }
+    }
+}
+
+impl<T> Id<T> {
+    /// Returns the id that `hashed` produced before it was namespaced by
+    /// prefix and keyed, so that existing documents can still be found.
+    pub fn legacy_hashed<H: Hash>(entity: H) -> Self {
         let inner = UntypedId::hashed(entity);
         let phantom = PhantomData;
         Id { inner, phantom }
     }
 }
 
+impl<T> Id<T> {
+    /// Returns the id of a related entity of type `U`, eg: one of several
+    /// children of this entity, distinguished by `discriminator`.
+    pub fn derive<U: Entity, H: Hash>(&self, discriminator: H) -> Id<U> {
+        let inner = self.inner.derive((U::PREFIX, discriminator));
+        let phantom = PhantomData;
+        Id { inner, phantom }
+    }
+}
+
 impl IdGen {
     pub fn new() -> Self {
         Default::default()
     }
 
+    /// Returns a generator whose ids strictly increase, even when several are
+    /// generated within the same clock tick, or the wall clock steps
+    /// backwards. In either case, we re-use the previous timestamp and
+    /// increment the random portion, similarly to monotonic ULIDs.
+    pub fn monotonic() -> Self {
+        let last = Some(Arc::new(Mutex::new(UntypedId::default())));
+        IdGen {
+            last,
+            ..Self::new()
+        }
+    }
+
+    pub fn with_sources<C: Clock + 'static, R: RandomSource + 'static>(
+        clock: C,
+        random: R,
+    ) -> Self {
+        let clock = Arc::new(clock);
+        let random = Arc::new(random);
+        let last = None;
+        let key = IdKey::default();
+        IdGen {
+            clock,
+            random,
+            last,
+            key,
+        }
+    }
+
+    /// Use the given key for hashed ids.
+    pub fn keyed(self, key: IdKey) -> Self {
+        IdGen { key, ..self }
+    }
+
+    pub fn hashed<T: Entity, H: Hash>(&self, entity: H) -> Id<T> {
+        Id::hashed_with(&self.key, entity)
+    }
+
+    /// Returns a generator that yields the same sequence of ids for a given
+    /// seed, for use in tests.
+    pub fn deterministic(seed: u64) -> Self {
+        Self::with_sources(StepClock::default(), SeededRandom::new(seed))
+    }
+
     pub fn generate<T>(&self) -> Id<T> {
         let inner = self.untyped();
         let phantom = PhantomData;
@@ -59,15 +183,70 @@ impl IdGen {
     }
 }
 
-impl<T> Id<T> {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let inner = UntypedId::from_bytes(bytes);
+impl Default for IdGen {
+    fn default() -> Self {
+        Self::with_sources(SystemClock, ThreadRandom)
+    }
+}
 
-        let phantom = PhantomData;
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
 
-        Id { inner, phantom }
+impl RandomSource for ThreadRandom {
+    fn next_u64(&self) -> u64 {
+        rand::random()
     }
+}
 
+impl StepClock {
+    pub fn new(start: SystemTime, step: Duration) -> Self {
+        let ticks = AtomicU64::new(0);
+        StepClock { start, step, ticks }
+    }
+}
+
+impl Default for StepClock {
+    fn default() -> Self {
+        // 2020-01-01T00:00:00Z
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+        Self::new(start, Duration::from_millis(1))
+    }
+}
+
+impl Clock for StepClock {
+    fn now(&self) -> SystemTime {
+        let ticks = self.ticks.fetch_add(1, AtomicOrdering::SeqCst);
+        u32::try_from(ticks)
+            .ok()
+            .and_then(|ticks| self.step.checked_mul(ticks))
+            .and_then(|elapsed| self.start.checked_add(elapsed))
+            .expect("StepClock out of range")
+    }
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        let state = AtomicU64::new(seed);
+        SeededRandom { state }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(0x9e37_79b9_7f4a_7c15, AtomicOrdering::SeqCst)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl<T> Id<T> {
     fn to_bytes(&self) -> Vec<u8> {
         self.inner.to_bytes()
     }
@@ -84,19 +263,11 @@ impl<T> Id<T> {
     }
 }
 
+/// As for `UntypedId`, the alternate form (`{:#}`) includes a check symbol.
 impl<T: Entity> fmt::Display for Id<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut buf = [0u8; ENCODED_BARE_ID_LEN];
-        BASE32_DNSSEC.encode_mut(&self.to_bytes(), &mut buf);
-
-        write!(
-            fmt,
-            "{}{}{}",
-            T::PREFIX,
-            DIVIDER,
-            String::from_utf8_lossy(&buf[..])
-        )?;
-        Ok(())
+        write!(fmt, "{}{}", T::PREFIX, DIVIDER)?;
+        fmt::Display::fmt(&self.inner, fmt)
     }
 }
 
@@ -123,12 +294,7 @@ impl<T: Entity> std::str::FromStr for Id<T> {
             return Err(IdParseError::Unparseable.into());
         }
 
-        let mut bytes = [0u8; 16];
-        BASE32_DNSSEC
-            .decode_mut(b64.as_bytes(), &mut bytes)
-            .map_err(IdParseError::from)?;
-
-        Ok(Self::from_bytes(&bytes[..]))
+        Ok(Self::from_untyped(b64.parse()?))
     }
 }
 
@@ -171,7 +337,11 @@ impl<T> Hash for Id<T> {
 
 impl<T: Entity> Serialize for Id<T> {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
     }
 }
 
@@ -190,7 +360,63 @@ impl<'de, T: Entity> Deserialize<'de> for Id<T> {
             }
         }
 
-        deserializer.deserialize_str(IdStrVisitor(PhantomData))
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdStrVisitor(PhantomData))
+        } else {
+            deserializer
+                .deserialize_bytes(IdBytesVisitor)
+                .map(Id::from_untyped)
+        }
+    }
+}
+
+impl AnyId {
+    /// As `Id::<T>::hashed_with`, for a prefix only known at runtime.
+    pub fn hashed_with<H: Hash>(prefix: &str, key: &IdKey, entity: H) -> Self {
+        let id = UntypedId::hashed_with(key, (prefix, entity));
+        let prefix = prefix.to_string();
+        AnyId { prefix, id }
+    }
+
+    /// Returns the typed id, if this id has `T`'s prefix.
+    pub fn typed<T: Entity>(&self) -> Option<Id<T>> {
+        if self.prefix == T::PREFIX {
+            Some(Id::from_untyped(self.id))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Entity> From<Id<T>> for AnyId {
+    fn from(src: Id<T>) -> Self {
+        let prefix = T::PREFIX.to_string();
+        let id = src.untyped();
+        AnyId { prefix, id }
+    }
+}
+
+impl fmt::Display for AnyId {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}{}", self.prefix, DIVIDER)?;
+        fmt::Display::fmt(&self.id, fmt)
+    }
+}
+
+impl std::str::FromStr for AnyId {
+    type Err = Error;
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let (prefix, bare) = match src.rfind(DIVIDER) {
+            Some(idx) => (&src[..idx], &src[idx + DIVIDER.len()..]),
+            None => return Err(IdParseError::Unparseable.into()),
+        };
+        if prefix.is_empty() {
+            return Err(IdParseError::InvalidPrefix.into());
+        }
+
+        let prefix = prefix.to_string();
+        let id = bare.parse::<UntypedId>()?;
+        Ok(AnyId { prefix, id })
     }
 }
 
@@ -240,6 +466,54 @@ mod test {
         assert_eq!(id, id2);
     }
 
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        canary: Id<Canary>,
+        note: String,
+    }
+
+    fn message() -> Message {
+        Message {
+            canary: IdGen::new().generate(),
+            note: "tweet".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trips_via_bincode() {
+        let msg = message();
+
+        let bytes = bincode::serialize(&msg).expect("bincode::serialize");
+        let msg2: Message = bincode::deserialize(&bytes).expect("bincode::deserialize");
+        assert_eq!(msg, msg2);
+    }
+
+    #[test]
+    fn round_trips_via_cbor() {
+        let msg = message();
+
+        let bytes = serde_cbor::to_vec(&msg).expect("serde_cbor::to_vec");
+        let msg2: Message = serde_cbor::from_slice(&bytes).expect("serde_cbor::from_slice");
+        assert_eq!(msg, msg2);
+    }
+
+    #[test]
+    fn round_trips_via_msgpack() {
+        let msg = message();
+
+        let bytes = rmp_serde::to_vec_named(&msg).expect("rmp_serde::to_vec_named");
+        let msg2: Message = rmp_serde::from_slice(&bytes).expect("rmp_serde::from_slice");
+        assert_eq!(msg, msg2);
+    }
+
+    #[test]
+    fn binary_form_should_be_compact() {
+        let id = IdGen::new().generate::<Canary>();
+
+        let bytes = serde_cbor::to_vec(&id).expect("serde_cbor::to_vec");
+        assert_eq!(bytes.len(), 1 + 16);
+    }
+
     #[test]
     fn round_trips_via_untyped() {
         let id = Id::<Canary>::hashed(&"boo");
@@ -280,6 +554,115 @@ mod test {
         assert!(id < id2 || id > id2);
     }
 
+    #[test]
+    fn hashed_should_be_namespaced_by_prefix() {
+        #[derive(Debug)]
+        struct Other;
+        impl Entity for Other {
+            const PREFIX: &'static str = "other";
+        }
+
+        assert_ne!(
+            Id::<Canary>::hashed("Hi!").untyped(),
+            Id::<Other>::hashed("Hi!").untyped()
+        );
+    }
+
+    #[test]
+    fn hashed_should_use_idgen_key() {
+        let idgen = IdGen::new().keyed(IdKey::from_secret("sekrit"));
+
+        assert_ne!(idgen.hashed::<Canary, _>("Hi!"), Id::hashed("Hi!"));
+        assert_eq!(
+            idgen.hashed::<Canary, _>("Hi!"),
+            Id::hashed_with(&IdKey::from_secret("sekrit"), "Hi!")
+        );
+    }
+
+    #[test]
+    fn legacy_hashed_should_match_previous_ids() {
+        // As created by earlier versions of the menu setup.
+        #[derive(Debug)]
+        struct Drink;
+        impl Entity for Drink {
+            const PREFIX: &'static str = "drink";
+        }
+
+        assert_eq!(
+            Id::<Drink>::legacy_hashed("Umbrella").to_string(),
+            "drink.0eghfh5gl9ivhq5nnvfqq7krfo"
+        );
+    }
+
+    #[test]
+    fn derive_should_distinguish_child_types() {
+        #[derive(Debug)]
+        struct Other;
+        impl Entity for Other {
+            const PREFIX: &'static str = "other";
+        }
+        let parent = IdGen::new().generate::<Canary>();
+
+        let canary = parent.derive::<Canary, _>(0u64);
+        let other = parent.derive::<Other, _>(0u64);
+
+        assert_ne!(canary.untyped(), other.untyped());
+        assert_eq!(canary.untyped().timestamp(), parent.untyped().timestamp());
+    }
+
+    #[test]
+    fn deterministic_should_repeat_sequence_for_seed() {
+        let a = IdGen::deterministic(42);
+        let b = IdGen::deterministic(42);
+
+        for _ in 0..16 {
+            assert_eq!(a.generate::<Canary>(), b.generate::<Canary>());
+        }
+    }
+
+    #[test]
+    fn deterministic_should_differ_between_seeds() {
+        let a = IdGen::deterministic(1).generate::<Canary>();
+        let b = IdGen::deterministic(2).generate::<Canary>();
+
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn deterministic_should_match_snapshot() {
+        let idgen = IdGen::deterministic(0);
+        let ids = (0..2)
+            .map(|_| idgen.generate::<Canary>().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ids,
+            vec![
+                "canary.2nipkddph8001oh0l0snm7edls".to_string(),
+                "canary.2nipkddpj5140rjojpla3eb5ug".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn should_use_clock_for_timestamp() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let idgen =
+            IdGen::with_sources(StepClock::new(start, Duration::from_secs(1)), ThreadRandom);
+
+        assert_eq!(idgen.untyped().timestamp(), start);
+        assert_eq!(idgen.untyped().timestamp(), start + Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "StepClock out of range")]
+    fn step_clock_should_panic_rather_than_wrap() {
+        let clock = StepClock::new(SystemTime::UNIX_EPOCH, Duration::from_secs(u64::MAX));
+
+        clock.now();
+        clock.now();
+    }
+
     #[test]
     fn to_string_should_be_prefixed_with_type_name() {
         let idgen = IdGen::new();
@@ -368,4 +751,97 @@ mod test {
             result,
         )
     }
+
+    #[test]
+    fn round_trips_via_checked_form() {
+        let id = IdGen::new().generate::<Canary>();
+        let s = format!("{:#}", id);
+
+        assert!(s.starts_with("canary."));
+        assert_eq!(s.parse::<Id<Canary>>().expect("parse id"), id);
+    }
+
+    #[test]
+    fn should_reject_mistyped_checked_form() {
+        let checked = format!("{:#}", Id::<Canary>::hashed("Hi!"));
+        // Swap the last two differing neighbours before the check symbol;
+        // the check symbol catches any such transposition.
+        let mut s = checked.clone().into_bytes();
+        let last = (1..s.len() - 1)
+            .rev()
+            .find(|&i| s[i] != s[i - 1] && s[i - 1] != b'.')
+            .expect("differing neighbours");
+        s.swap(last, last - 1);
+        let s = String::from_utf8(s).expect("utf8");
+
+        assert_ne!(s, checked);
+        assert!(s.parse::<Id<Canary>>().is_err(), "Parsing {:?}", s);
+    }
+
+    #[test]
+    fn any_id_round_trips_via_checked_form() {
+        let id = AnyId::from(IdGen::new().generate::<Canary>());
+
+        let s = format!("{:#}", id);
+        assert_eq!(s.parse::<AnyId>().expect("parse id"), id);
+    }
+
+    #[test]
+    fn any_id_round_trips_via_to_from_str() {
+        let id = AnyId::from(Id::<Canary>::hashed("Hi!"));
+        let s = id.to_string();
+        let id2 = s.parse::<AnyId>().expect("parse id");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn any_id_should_display_as_typed_id() {
+        let id = Id::<Canary>::hashed("Hi!");
+        assert_eq!(AnyId::from(id).to_string(), id.to_string());
+    }
+
+    #[test]
+    fn any_id_should_parse_hyphenated_prefix() {
+        let id = "drink-preparation.0000000000001q5nnvfqq7krfo"
+            .parse::<AnyId>()
+            .expect("parse id");
+        assert_eq!(id.prefix, "drink-preparation");
+    }
+
+    #[test]
+    fn any_id_should_convert_to_matching_type_only() {
+        #[derive(Debug)]
+        struct Other;
+        impl Entity for Other {
+            const PREFIX: &'static str = "other";
+        }
+        let id = Id::<Canary>::hashed("Hi!");
+        let any = AnyId::from(id);
+
+        assert_eq!(any.typed::<Canary>(), Some(id));
+        assert_eq!(any.typed::<Other>(), None);
+    }
+
+    #[test]
+    fn any_id_hashed_should_match_typed() {
+        let key = IdKey::from_secret("sekrit");
+
+        assert_eq!(
+            AnyId::hashed_with("canary", &key, "Hi!"),
+            AnyId::from(Id::<Canary>::hashed_with(&key, "Hi!"))
+        );
+    }
+
+    #[test]
+    fn any_id_should_reject_missing_prefix() {
+        for s in &["0000000000001q5nnvfqq7krfo", ".0000000000001q5nnvfqq7krfo"] {
+            let result = s.parse::<AnyId>();
+            assert!(
+                result.is_err(),
+                "Parsing {:?} should return error; got {:?}",
+                s,
+                result,
+            )
+        }
+    }
 }
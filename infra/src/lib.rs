@@ -1,4 +1,9 @@
 pub mod documents;
 pub mod ids;
 pub mod persistence;
+pub mod registry;
+pub mod supervisor;
 pub mod untyped_ids;
+#[cfg(feature = "uuid")]
+pub mod uuids;
+pub mod workers;
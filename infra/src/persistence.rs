@@ -1,7 +1,12 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
-use std::time::Duration;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use fallible_iterator::FallibleIterator;
 use log::*;
 use postgres::types::{FromSql, IsNull, ToSql, Type};
@@ -11,11 +16,25 @@ use serde::{de::DeserializeOwned, Serialize};
 
 use crate::documents::{HasMeta, Version};
 use crate::ids::{Entity, Id};
+use crate::workers::{self, WorkerLease, WorkerStatus};
 
 pub trait Storage {
     fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error>;
+    /// Loads up to `limit` documents with ids in the given range, in id (and
+    /// so creation time) order.
+    fn load_range<D: DeserializeOwned + Entity>(
+        &self,
+        from: Bound<&Id<D>>,
+        to: Bound<&Id<D>>,
+        limit: usize,
+    ) -> Result<Vec<D>, Error>;
     fn save<D: Serialize + Entity + HasMeta>(&self, document: &mut D) -> Result<(), Error>;
 }
+/// Reports on the store as a whole, eg: for monitoring.
+pub trait StorageStatus {
+    fn outbox_status(&self) -> Result<Vec<OutboxStatus>, Error>;
+    fn workers(&self) -> Result<Vec<WorkerStatus>, Error>;
+}
 pub trait StoragePending {
     fn subscribe<
         D: DeserializeOwned + Serialize + Entity + HasMeta,
@@ -24,6 +43,43 @@ pub trait StoragePending {
         &mut self,
         handler: F,
     ) -> Result<(), Error>;
+    fn listen(&mut self, channel: &str) -> Result<(), Error>;
+    fn process_pending<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        handler: &F,
+    ) -> Result<usize, Error>;
+    fn process_notified<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        ids: &[Id<D>],
+        handler: &F,
+    ) -> Result<usize, Error>;
+    fn await_notification(&mut self, timeout: Duration) -> Result<Option<Notification>, Error>;
+    /// Notifications that have already arrived, without waiting for more.
+    fn buffered_notifications(&mut self) -> Result<Vec<Notification>, Error>;
+    fn notification_timeout(&self) -> Duration;
+    fn heartbeat(&mut self, prefix: &str) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub channel: String,
+    pub payload: String,
+}
+
+/// Summarises the documents of a given entity type that have unsent
+/// messages in their outbox.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxStatus {
+    pub prefix: String,
+    pub pending: u64,
+    /// How long the oldest pending document has had unsent messages.
+    pub oldest_pending_age: Option<Duration>,
 }
 
 #[derive(err_derive::Error, Debug, PartialEq, Eq)]
@@ -32,43 +88,127 @@ pub struct ConcurrencyError;
 
 pub struct Documents {
     connection: postgres::Connection,
+    pending: PendingConfig,
+    manager: Arc<PostgresConnectionManager>,
+    schema: Option<String>,
+    lease: Option<WorkerLease>,
+    heartbeats: HashMap<String, Instant>,
+}
+
+/// Controls how pending documents are claimed by `subscribe`.
+#[derive(Debug, Clone)]
+pub struct PendingConfig {
+    /// The maximum number of documents claimed in a single transaction.
+    pub batch_size: usize,
+    /// How long to wait for a notification before re-scanning for work.
+    pub notification_timeout: Duration,
+    /// How long a worker may go without a heartbeat before its registry
+    /// entry is considered stale.
+    pub lease_ttl: Duration,
 }
 
 #[derive(Debug)]
-pub struct DocumentConnectionManager(PostgresConnectionManager);
+pub struct DocumentConnectionManager {
+    pg: Arc<PostgresConnectionManager>,
+    pending: PendingConfig,
+    lease: Option<WorkerLease>,
+}
 
 struct Jsonb<T>(T);
 
 const SETUP_SQL: &str = include_str!("persistence.sql");
-const LOAD_SQL: &str = "SELECT body FROM documents WHERE id = $1";
-const LOAD_NEXT_SQL: &str = "SELECT id, body
+// Documents are keyed by a small code per entity prefix, and the binary
+// form of the id.
+const ENSURE_ENTITY_SQL: &str = "INSERT INTO entities (prefix)
+                                    SELECT $1::text
+                                    WHERE NOT EXISTS (SELECT 1 FROM entities WHERE prefix = $1::text)
+                                    ON CONFLICT (prefix) DO NOTHING";
+const LOAD_SQL: &str = "SELECT body FROM documents
+                            WHERE entity = (SELECT code FROM entities WHERE prefix = $1::text)
+                            AND uid = $2";
+const LOAD_RANGE_SQL: &str = "SELECT body FROM documents
+                            WHERE entity = (SELECT code FROM entities WHERE prefix = $1::text)
+                            AND uid >= $2 AND uid < $3
+                            ORDER BY uid
+                            LIMIT $4";
+const LOAD_NEXT_SQL: &str = "SELECT body ->> '_id', body
                                      FROM documents
                                      WHERE jsonb_array_length(body -> '_outgoing') > 0
-                                     AND id like $1::text || '.%'
+                                     AND entity = (SELECT code FROM entities WHERE prefix = $1::text)
+                                     FOR UPDATE SKIP LOCKED
+                                     LIMIT $2
+";
+const LOAD_NOTIFIED_SQL: &str = "SELECT body ->> '_id', body
+                                     FROM documents
+                                     WHERE jsonb_array_length(body -> '_outgoing') > 0
+                                     AND entity = (SELECT code FROM entities WHERE prefix = $1::text)
+                                     AND uid = ANY($2::bytea[])
                                      FOR UPDATE SKIP LOCKED
-                                     LIMIT 1
 ";
 const INSERT_SQL: &str = "WITH a as (
-                                SELECT $1::jsonb as body
+                                SELECT $1::jsonb as body,
+                                    (SELECT code FROM entities WHERE prefix = $2::text) as entity,
+                                    $3::bytea as uid
                                 )
-                                INSERT INTO documents AS d (id, body)
-                                SELECT a.body ->> '_id', a.body
+                                INSERT INTO documents AS d (entity, uid, body, pending_since)
+                                SELECT a.entity, a.uid, a.body,
+                                    CASE WHEN jsonb_array_length(a.body -> '_outgoing') > 0
+                                        THEN now() END
                                 FROM a
                                 WHERE NOT EXISTS (
-                                    SELECT 1 FROM documents d where d.id = a.body ->> '_id'
-                                )";
+                                    SELECT 1 FROM documents d
+                                    WHERE d.entity = a.entity AND d.uid = a.uid
+                                )
+                                ON CONFLICT DO NOTHING";
 const UPDATE_SQL: &str = "WITH a as (
                                     SELECT $1::jsonb as body, $2::jsonb as expected_version
                                     )
                                     UPDATE documents AS d
-                                        SET body = a.body
+                                        SET body = a.body,
+                                            pending_since =
+                                                CASE WHEN jsonb_array_length(a.body -> '_outgoing') > 0
+                                                    THEN coalesce(d.pending_since, now()) END
                                         FROM a
-                                        WHERE id = a.body ->> '_id'
+                                        WHERE d.entity = (SELECT code FROM entities WHERE prefix = $3::text)
+                                        AND d.uid = $4::bytea
                                         AND d.body -> '_version' = expected_version
                                     ";
+const OUTBOX_STATUS_SQL: &str = "SELECT e.prefix,
+                                        count(*),
+                                        extract(epoch FROM now() - min(d.pending_since))::float8
+                                    FROM documents d
+                                    JOIN entities e ON e.code = d.entity
+                                    WHERE jsonb_array_length(d.body -> '_outgoing') > 0
+                                    GROUP BY 1
+                                    ORDER BY 1
+";
+const HEARTBEAT_SQL: &str = "INSERT INTO workers AS w
+                                    (worker_id, prefix, host, pid, started_at, heartbeat_at,
+                                     last_claimed, last_claimed_at)
+                                VALUES ($1, $2, $3, $4, to_timestamp($5::float8), now(),
+                                        $6::text, CASE WHEN $6::text IS NOT NULL THEN now() END)
+                                ON CONFLICT (worker_id, prefix) DO UPDATE
+                                    SET heartbeat_at = now(),
+                                        last_claimed = coalesce(excluded.last_claimed, w.last_claimed),
+                                        last_claimed_at = coalesce(excluded.last_claimed_at, w.last_claimed_at)
+";
+const EXPIRE_WORKERS_SQL: &str = "DELETE FROM workers
+                                    WHERE heartbeat_at < now() - $1::float8 * interval '1 second'";
+const LIST_WORKERS_SQL: &str = "SELECT worker_id, host, pid, prefix,
+                                        extract(epoch FROM started_at)::float8,
+                                        extract(epoch FROM heartbeat_at)::float8,
+                                        last_claimed,
+                                        extract(epoch FROM last_claimed_at)::float8
+                                    FROM workers
+                                    WHERE heartbeat_at >= now() - $1::float8 * interval '1 second'
+                                    ORDER BY host, pid, prefix
+";
 static SEND_NOTIFY_SQL: &str = "SELECT pg_notify($1 :: text, $2 :: text)";
 static LISTEN_SQL: &str = "SELECT do_listen($1 :: text)";
 
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
 impl Documents {
     pub fn setup(&self) -> Result<(), Error> {
         for stmt in SETUP_SQL.split("\n\n") {
@@ -95,12 +235,19 @@ impl Documents {
 
         document.meta_mut().increment_version();
 
+        let uid = document.meta().id.untyped().to_bytes();
         let rows = if current_version == Version::default() {
+            t.prepare_cached(ENSURE_ENTITY_SQL)?
+                .execute(&[&D::PREFIX])?;
             t.prepare_cached(INSERT_SQL)?
-                .execute(&[&Jsonb(&document)])?
+                .execute(&[&Jsonb(&document), &D::PREFIX, &uid])?
         } else {
-            t.prepare_cached(UPDATE_SQL)?
-                .execute(&[&Jsonb(&document), &Jsonb(&current_version)])?
+            t.prepare_cached(UPDATE_SQL)?.execute(&[
+                &Jsonb(&document),
+                &Jsonb(&current_version),
+                &D::PREFIX,
+                &uid,
+            ])?
         };
         debug!("Query modified {} rows", rows);
         if rows == 0 {
@@ -114,7 +261,7 @@ impl Documents {
 
     pub fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
         let load = self.connection.prepare_cached(LOAD_SQL)?;
-        let res = load.query(&[&id.to_string()])?;
+        let res = load.query(&[&D::PREFIX, &id.untyped().to_bytes()])?;
 
         if let Some(row) = res.iter().next() {
             let Jsonb(doc) = row.get(0);
@@ -125,6 +272,55 @@ impl Documents {
         }
     }
 
+    pub fn load_range<D: DeserializeOwned + Entity>(
+        &self,
+        from: Bound<&Id<D>>,
+        to: Bound<&Id<D>>,
+        limit: usize,
+    ) -> Result<Vec<D>, Error> {
+        let load = self.connection.prepare_cached(LOAD_RANGE_SQL)?;
+        let res = load.query(&[
+            &D::PREFIX,
+            &lower_key(from),
+            &upper_key(to),
+            &(limit as i64),
+        ])?;
+
+        let docs = res
+            .iter()
+            .map(|row| {
+                let Jsonb(doc) = row.get(0);
+                doc
+            })
+            .collect();
+        Ok(docs)
+    }
+
+    pub fn outbox_status(&self) -> Result<Vec<OutboxStatus>, Error> {
+        let query = self.connection.prepare_cached(OUTBOX_STATUS_SQL)?;
+        let res = query.query(&[])?;
+
+        let statuses = res
+            .iter()
+            .map(|row| {
+                let prefix: String = row.get(0);
+                let pending: i64 = row.get(1);
+                let age_secs: Option<f64> = row.get(2);
+                OutboxStatus {
+                    prefix,
+                    pending: pending as u64,
+                    oldest_pending_age: age_secs.map(|s| Duration::from_secs_f64(s.max(0.0))),
+                }
+            })
+            .collect();
+
+        Ok(statuses)
+    }
+
+    /// Runs the handler over pending documents as they are notified. A full
+    /// scan of the prefix is made on startup, and then whenever no scan has
+    /// happened within the notification timeout. If the connection is lost,
+    /// we reconnect and listen again, rather than giving up.
     fn subscribe<
         D: DeserializeOwned + Serialize + Entity + HasMeta,
         F: Fn(&mut D) -> Result<(), Error>,
@@ -132,62 +328,369 @@ impl Documents {
         &mut self,
         f: F,
     ) -> Result<(), Error> {
+        self.listen(D::PREFIX)?;
+
+        let mut last_scan = None;
+        loop {
+            if let Err(e) = self.subscribe_step(&f, &mut last_scan) {
+                if self.is_connected() {
+                    return Err(e);
+                }
+                warn!("Lost connection subscribed to {}: {:?}", D::PREFIX, e);
+                self.reconnect_and_listen(D::PREFIX);
+                last_scan = None;
+            }
+        }
+    }
+
+    fn subscribe_step<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        f: &F,
+        last_scan: &mut Option<Instant>,
+    ) -> Result<(), Error> {
+        if let Err(e) = self.heartbeat(D::PREFIX, None) {
+            warn!("Error sending heartbeat for {}: {:?}", D::PREFIX, e);
+        }
+
+        let scan_interval = self.pending.notification_timeout;
+        let scan_due = last_scan
+            .map(|t| t.elapsed() >= scan_interval)
+            .unwrap_or(true);
+        if scan_due {
+            debug!("Scanning for pending {} documents", D::PREFIX);
+            while self.process_pending(f)? >= self.pending.batch_size {}
+            *last_scan = Some(Instant::now());
+        }
+
+        let timeout = last_scan
+            .map(|t| scan_interval.checked_sub(t.elapsed()).unwrap_or_default())
+            .unwrap_or_default();
+        let mut notifications = Vec::new();
+        notifications.extend(self.await_notification(timeout)?);
+        notifications.extend(self.buffered_notifications()?);
+
+        let mut ids = Vec::new();
+        for notif in notifications {
+            debug!("Found notification: {:?}", notif);
+            if notif.channel != D::PREFIX {
+                continue;
+            }
+            match notif.payload.parse::<Id<D>>() {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    warn!("Unparseable notification payload {:?}: {:?}", notif, e);
+                    *last_scan = None;
+                }
+            }
+        }
+        ids.sort();
+        ids.dedup();
+
+        for chunk in ids.chunks(cmp::max(self.pending.batch_size, 1)) {
+            self.process_notified(chunk, f)?;
+        }
+
+        Ok(())
+    }
+
+    fn listen(&mut self, channel: &str) -> Result<(), Error> {
         self.connection
             .prepare_cached(LISTEN_SQL)?
-            .execute(&[&D::PREFIX])?;
+            .execute(&[&channel])?;
+        Ok(())
+    }
 
-        loop {
-            {
-                let t = self.connection.transaction()?;
-                let load = t.prepare_cached(LOAD_NEXT_SQL)?;
-
-                let res = load.query(&[&D::PREFIX])?;
-
-                for row in res.iter() {
-                    let id: String = row.get(0);
-                    debug!("Considering document: {}", id);
-                    let Jsonb(mut doc) = row.get(1);
-                    match f(&mut doc) {
-                        Ok(()) => self.save_in_xact(&t, &mut doc),
-                        Err(e) => {
-                            if e.root_cause().downcast_ref::<ConcurrencyError>().is_some() {
-                                warn!("Ignoring concurrency error: {:?}", e);
-                                Ok(())
-                            } else {
-                                Err(e)
-                            }
+    /// Claims up to a batch of pending documents with the given prefix, and
+    /// runs the handler over each of them in a single transaction. Each
+    /// document is handled within its own savepoint, so a failure only
+    /// discards the changes for that document; the rest of the batch is
+    /// committed before the first such error is returned.
+    ///
+    /// Returns the number of documents that were handled successfully.
+    fn process_pending<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        f: &F,
+    ) -> Result<usize, Error> {
+        let batch_size = self.pending.batch_size as i64;
+        self.process_claimed(LOAD_NEXT_SQL, &[&D::PREFIX, &batch_size], f)
+    }
+
+    /// As `process_pending`, but only considers the given documents, eg: as
+    /// named by notifications. Documents that have no pending messages, or
+    /// are locked by another worker, are skipped.
+    fn process_notified<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        ids: &[Id<D>],
+        f: &F,
+    ) -> Result<usize, Error> {
+        let uids = ids
+            .iter()
+            .map(|id| id.untyped().to_bytes())
+            .collect::<Vec<_>>();
+        self.process_claimed(LOAD_NOTIFIED_SQL, &[&D::PREFIX, &uids], f)
+    }
+
+    fn process_claimed<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        query: &str,
+        params: &[&dyn ToSql],
+        f: &F,
+    ) -> Result<usize, Error> {
+        let (handled, first_error, last_claimed) = {
+            let t = self.connection.transaction()?;
+            let load = t.prepare_cached(query)?;
+
+            let res = load.query(params)?;
+
+            let mut handled = 0;
+            let mut first_error = None;
+            let mut last_claimed = None;
+            for row in res.iter() {
+                let id: String = row.get(0);
+                debug!("Considering document: {}", id);
+                last_claimed = Some(id.clone());
+                let Jsonb(mut doc) = row.get(1);
+
+                let sp = t.savepoint("pending_document")?;
+                match f(&mut doc).and_then(|()| self.save_in_xact(&sp, &mut doc)) {
+                    Ok(()) => {
+                        sp.commit()?;
+                        handled += 1;
+                    }
+                    Err(e) => {
+                        sp.finish()?;
+                        if e.root_cause().downcast_ref::<ConcurrencyError>().is_some() {
+                            warn!("Ignoring concurrency error: {:?}", e);
+                        } else {
+                            error!("Error handling document {}: {:?}", id, e);
+                            first_error.get_or_insert(e);
                         }
-                    }?
+                    }
                 }
-                t.commit()?;
-                debug!("Commited transaction");
             }
+            t.commit()?;
+            debug!("Commited transaction; handled {} documents", handled);
 
-            let notif = self
-                .connection
-                .notifications()
-                .timeout_iter(Duration::from_secs(60))
-                .next()?;
+            (handled, first_error, last_claimed)
+        };
 
-            debug!("Found notification: {:?}", notif);
+        // The batch is committed by now, so a missed heartbeat mustn't hide
+        // what we handled.
+        if last_claimed.is_some() {
+            if let Err(e) = self.heartbeat(D::PREFIX, last_claimed.as_deref()) {
+                warn!("Error sending heartbeat for {}: {:?}", D::PREFIX, e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(handled),
+        }
+    }
+
+    fn await_notification(&mut self, timeout: Duration) -> Result<Option<Notification>, Error> {
+        let notif = self
+            .connection
+            .notifications()
+            .timeout_iter(timeout)
+            .next()?
+            .map(|n| Notification {
+                channel: n.channel,
+                payload: n.payload,
+            });
+        Ok(notif)
+    }
+
+    fn buffered_notifications(&mut self) -> Result<Vec<Notification>, Error> {
+        let notifs = self
+            .connection
+            .notifications()
+            .iter()
+            .map(|n| Notification {
+                channel: n.channel,
+                payload: n.payload,
+            })
+            .collect()?;
+        Ok(notifs)
+    }
+
+    fn notification_timeout(&self) -> Duration {
+        self.pending.notification_timeout
+    }
+
+    /// Records that this worker is alive and consuming the given prefix, and
+    /// removes any stale registry entries. Unless a document has been
+    /// claimed, heartbeats are only written every third of the lease TTL.
+    fn heartbeat(&mut self, prefix: &str, last_claimed: Option<&str>) -> Result<(), Error> {
+        let lease = match self.lease.as_ref() {
+            Some(lease) => lease,
+            None => return Ok(()),
+        };
+        let ttl = self.pending.lease_ttl;
+        let recent = self
+            .heartbeats
+            .get(prefix)
+            .map(|t| t.elapsed() < ttl / 3)
+            .unwrap_or(false);
+        if last_claimed.is_none() && recent {
+            return Ok(());
+        }
+
+        let t = self.connection.transaction()?;
+        t.prepare_cached(HEARTBEAT_SQL)?.execute(&[
+            &lease.worker_id.to_string(),
+            &prefix,
+            &lease.host,
+            &(lease.pid as i32),
+            &workers::to_epoch_secs(lease.started_at),
+            &last_claimed,
+        ])?;
+        let expired = t
+            .prepare_cached(EXPIRE_WORKERS_SQL)?
+            .execute(&[&ttl.as_secs_f64()])?;
+        t.commit()?;
+        if expired > 0 {
+            info!("Expired {} stale worker entries", expired);
+        }
+
+        self.heartbeats.insert(prefix.to_string(), Instant::now());
+        Ok(())
+    }
+
+    pub fn workers(&self) -> Result<Vec<WorkerStatus>, Error> {
+        let query = self.connection.prepare_cached(LIST_WORKERS_SQL)?;
+        let res = query.query(&[&self.pending.lease_ttl.as_secs_f64()])?;
+
+        let mut statuses = Vec::new();
+        for row in res.iter() {
+            let worker_id: String = row.get(0);
+            let pid: i32 = row.get(2);
+            let started_at: f64 = row.get(4);
+            let heartbeat_at: f64 = row.get(5);
+            let last_claimed_at: Option<f64> = row.get(7);
+            statuses.push(WorkerStatus {
+                worker_id: worker_id.parse()?,
+                host: row.get(1),
+                pid: pid as u32,
+                prefix: row.get(3),
+                started_at: workers::from_epoch_secs(started_at),
+                heartbeat_at: workers::from_epoch_secs(heartbeat_at),
+                last_claimed: row.get(6),
+                last_claimed_at: last_claimed_at.map(workers::from_epoch_secs),
+            });
+        }
+
+        Ok(statuses)
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.connection.is_desynchronized() && self.connection.batch_execute("").is_ok()
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.connection = r2d2::ManageConnection::connect(&*self.manager)?;
+        if let Some(schema) = self.schema.clone() {
+            self.use_schema(&schema)?;
+        }
+        Ok(())
+    }
+
+    fn reconnect_and_listen(&mut self, channel: &str) {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            thread::sleep(delay);
+            match self.reconnect().and_then(|()| self.listen(channel)) {
+                Ok(()) => {
+                    info!("Reconnected and listening on {}", channel);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Error reconnecting; retrying in {:?}: {:?}", delay, e);
+                    delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                }
+            }
         }
     }
 
+    fn use_schema(&mut self, schema: &str) -> Result<(), postgres::Error> {
+        self.connection
+            .execute(&format!("SET search_path TO \"{}\"", schema), &[])?;
+        self.schema = Some(schema.to_string());
+        Ok(())
+    }
+
     pub fn get_ref(&self) -> &postgres::Connection {
         &self.connection
     }
 }
 
+// Binary ids are all the same length, so appending a zero byte gives the
+// least key after an id, and a longer run of 0xff sorts after every id.
+fn lower_key<D>(bound: Bound<&Id<D>>) -> Vec<u8> {
+    match bound {
+        Bound::Included(id) => id.untyped().to_bytes(),
+        Bound::Excluded(id) => {
+            let mut key = id.untyped().to_bytes();
+            key.push(0);
+            key
+        }
+        Bound::Unbounded => Vec::new(),
+    }
+}
+
+fn upper_key<D>(bound: Bound<&Id<D>>) -> Vec<u8> {
+    match bound {
+        Bound::Included(id) => {
+            let mut key = id.untyped().to_bytes();
+            key.push(0);
+            key
+        }
+        Bound::Excluded(id) => id.untyped().to_bytes(),
+        Bound::Unbounded => vec![0xff; 17],
+    }
+}
+
 impl Storage for Documents {
     fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
         Documents::load(self, id)
     }
 
+    fn load_range<D: DeserializeOwned + Entity>(
+        &self,
+        from: Bound<&Id<D>>,
+        to: Bound<&Id<D>>,
+        limit: usize,
+    ) -> Result<Vec<D>, Error> {
+        Documents::load_range(self, from, to, limit)
+    }
+
     fn save<D: Serialize + Entity + HasMeta>(&self, document: &mut D) -> Result<(), Error> {
         Documents::save(self, document)
     }
 }
 
+impl StorageStatus for Documents {
+    fn outbox_status(&self) -> Result<Vec<OutboxStatus>, Error> {
+        Documents::outbox_status(self)
+    }
+
+    fn workers(&self) -> Result<Vec<WorkerStatus>, Error> {
+        Documents::workers(self)
+    }
+}
+
 impl StoragePending for Documents {
     fn subscribe<
         D: DeserializeOwned + Serialize + Entity + HasMeta,
@@ -198,11 +701,93 @@ impl StoragePending for Documents {
     ) -> Result<(), Error> {
         Documents::subscribe(self, f)
     }
+
+    fn listen(&mut self, channel: &str) -> Result<(), Error> {
+        Documents::listen(self, channel)
+    }
+
+    fn process_pending<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        f: &F,
+    ) -> Result<usize, Error> {
+        Documents::process_pending(self, f)
+    }
+
+    fn process_notified<
+        D: DeserializeOwned + Serialize + Entity + HasMeta,
+        F: Fn(&mut D) -> Result<(), Error>,
+    >(
+        &mut self,
+        ids: &[Id<D>],
+        f: &F,
+    ) -> Result<usize, Error> {
+        Documents::process_notified(self, ids, f)
+    }
+
+    fn await_notification(&mut self, timeout: Duration) -> Result<Option<Notification>, Error> {
+        Documents::await_notification(self, timeout)
+    }
+
+    fn buffered_notifications(&mut self) -> Result<Vec<Notification>, Error> {
+        Documents::buffered_notifications(self)
+    }
+
+    fn notification_timeout(&self) -> Duration {
+        Documents::notification_timeout(self)
+    }
+
+    fn heartbeat(&mut self, prefix: &str) -> Result<(), Error> {
+        Documents::heartbeat(self, prefix, None)
+    }
+}
+
+impl PendingConfig {
+    /// Idle workers only heartbeat as often as they wake for notifications,
+    /// and skip heartbeats within a third of the lease TTL of the last; so
+    /// the TTL must outlast both, or live workers will appear to expire.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.notification_timeout >= self.lease_ttl * 2 / 3 {
+            return Err(anyhow!(
+                "Lease TTL {:?} is too short for notification timeout {:?}; it must be over half as long again",
+                self.lease_ttl,
+                self.notification_timeout
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PendingConfig {
+    fn default() -> Self {
+        PendingConfig {
+            batch_size: 32,
+            notification_timeout: Duration::from_secs(60),
+            lease_ttl: Duration::from_secs(180),
+        }
+    }
 }
 
 impl DocumentConnectionManager {
     pub fn new(pg: PostgresConnectionManager) -> Self {
-        DocumentConnectionManager(pg)
+        let pg = Arc::new(pg);
+        let pending = PendingConfig::default();
+        let lease = None;
+        DocumentConnectionManager { pg, pending, lease }
+    }
+
+    pub fn pending(mut self, pending: PendingConfig) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    /// Registers connections that process pending documents in the worker
+    /// registry under the given lease.
+    pub fn lease(mut self, lease: WorkerLease) -> Self {
+        self.lease = Some(lease);
+        self
     }
 }
 impl r2d2::ManageConnection for DocumentConnectionManager {
@@ -210,19 +795,31 @@ impl r2d2::ManageConnection for DocumentConnectionManager {
     type Error = postgres::Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let connection = self.0.connect()?;
-        Ok(Documents { connection })
+        let connection = self.pg.connect()?;
+        let pending = self.pending.clone();
+        let manager = self.pg.clone();
+        let schema = None;
+        let lease = self.lease.clone();
+        let heartbeats = HashMap::new();
+        Ok(Documents {
+            connection,
+            pending,
+            manager,
+            schema,
+            lease,
+            heartbeats,
+        })
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
         Ok(PostgresConnectionManager::is_valid(
-            &self.0,
+            &self.pg,
             &mut conn.connection,
         )?)
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        PostgresConnectionManager::has_broken(&self.0, &mut conn.connection)
+        PostgresConnectionManager::has_broken(&self.pg, &mut conn.connection)
     }
 }
 
@@ -272,12 +869,38 @@ where
         conn.load(id)
     }
 
+    fn load_range<D: DeserializeOwned + Entity>(
+        &self,
+        from: Bound<&Id<D>>,
+        to: Bound<&Id<D>>,
+        limit: usize,
+    ) -> Result<Vec<D>, Error> {
+        let conn = self.get()?;
+        conn.load_range(from, to, limit)
+    }
+
     fn save<D: Serialize + Entity + HasMeta>(&self, document: &mut D) -> Result<(), Error> {
         let conn = self.get()?;
         conn.save(document)
     }
 }
 
+impl<M> StorageStatus for r2d2::Pool<M>
+where
+    M: r2d2::ManageConnection,
+    M::Connection: StorageStatus,
+{
+    fn outbox_status(&self) -> Result<Vec<OutboxStatus>, Error> {
+        let conn = self.get()?;
+        conn.outbox_status()
+    }
+
+    fn workers(&self) -> Result<Vec<WorkerStatus>, Error> {
+        let conn = self.get()?;
+        conn.workers()
+    }
+}
+
 #[derive(Debug)]
 pub struct UseSchema(pub String);
 
@@ -306,8 +929,7 @@ impl r2d2::CustomizeConnection<Documents, postgres::Error> for UseSchema {
                 break;
             }
         }
-        conn.connection
-            .execute(&format!("SET search_path TO \"{}\"", self.0), &[])?;
+        conn.use_schema(&self.0)?;
         Ok(())
     }
 }
@@ -337,7 +959,12 @@ mod test {
         let pool = r2d2::Pool::builder()
             .max_size(2)
             .connection_customizer(Box::new(UseSchema(schema.to_string())))
-            .build(DocumentConnectionManager(manager))?;
+            .build(
+                DocumentConnectionManager::new(manager).pending(PendingConfig {
+                    batch_size: 4,
+                    ..Default::default()
+                }),
+            )?;
 
         let conn = pool.get()?;
         cleanup(&conn.connection, schema)?;
@@ -361,7 +988,7 @@ mod test {
         for row in rels.iter() {
             let schema = row.get::<_, String>(0);
             let table = row.get::<_, String>(1);
-            t.execute(&format!("DROP TABLE {}.{}", schema, table), &[])?;
+            t.execute(&format!("DROP TABLE {}.{} CASCADE", schema, table), &[])?;
         }
 
         let funcs = t.query(
@@ -416,6 +1043,43 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn load_range_should_return_documents_in_id_order() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("load_range_should_return_documents_in_id_order")?;
+        let docs = pool.get()?;
+
+        let idgen = ids::IdGen::monotonic();
+        let mut ids = Vec::new();
+        for name in &["a", "b", "c", "d"] {
+            let mut doc = ADocument {
+                meta: DocMeta::new_with_id(idgen.generate()),
+                name: name.to_string(),
+            };
+            docs.save(&mut doc)?;
+            ids.push(doc.meta.id);
+        }
+        let names = |loaded: Vec<ADocument>| loaded.into_iter().map(|d| d.name).collect::<Vec<_>>();
+
+        assert_eq!(
+            names(docs.load_range(Bound::Unbounded, Bound::Unbounded, 10)?),
+            vec!["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            names(docs.load_range(Bound::Included(&ids[1]), Bound::Excluded(&ids[3]), 10)?),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            names(docs.load_range(Bound::Excluded(&ids[1]), Bound::Included(&ids[3]), 10)?),
+            vec!["c", "d"]
+        );
+        assert_eq!(
+            names(docs.load_range(Bound::Unbounded, Bound::Unbounded, 3)?),
+            vec!["a", "b", "c"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn save_load() -> Result<(), Error> {
         env_logger::try_init().unwrap_or_default();
@@ -483,6 +1147,70 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn migration_should_backfill_binary_ids() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let schema = "migration_should_backfill_binary_ids";
+        let pool = pool(schema)?;
+        let docs = pool.get()?;
+
+        // Rewind to the schema as it was with textual ids.
+        cleanup(&docs.connection, schema)?;
+        for stmt in SETUP_SQL
+            .split("\n\n")
+            .take_while(|s| !s.contains("'0008 "))
+        {
+            docs.connection.batch_execute(stmt)?;
+        }
+        let mut some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Dave".to_string(),
+        };
+        some_doc.meta.increment_version();
+        docs.connection.execute(
+            "INSERT INTO documents (id, body) VALUES ($1, $2)",
+            &[&some_doc.meta.id.to_string(), &Jsonb(&some_doc)],
+        )?;
+
+        docs.setup()?;
+
+        let loaded = docs.load(&some_doc.meta.id)?;
+        assert_eq!(Some(some_doc.name), loaded.map(|d: ADocument| d.name));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_body_ids_that_disagree_with_keys() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_reject_body_ids_that_disagree_with_keys")?;
+        let docs = pool.get()?;
+
+        let mut some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Dave".to_string(),
+        };
+        docs.save(&mut some_doc)?;
+
+        let other_id = IDGEN.generate::<ADocument>().to_string();
+        let moved_uid = docs.connection.execute(
+            "UPDATE documents SET body = jsonb_set(body, '{_id}', to_jsonb($1::text))",
+            &[&other_id],
+        );
+        assert!(moved_uid.is_err(), "Changed uid: {:?}", moved_uid);
+
+        docs.connection
+            .execute("INSERT INTO entities (prefix) VALUES ('other')", &[])?;
+        let moved_entity = docs.connection.execute(
+            "UPDATE documents SET entity = (SELECT code FROM entities WHERE prefix = 'other')",
+            &[],
+        );
+        assert!(moved_entity.is_err(), "Changed entity: {:?}", moved_entity);
+
+        let loaded = docs.load(&some_doc.meta.id)?;
+        assert_eq!(Some(some_doc.name), loaded.map(|d: ADocument| d.name));
+        Ok(())
+    }
+
     #[test]
     fn supports_connection() -> Result<(), Error> {
         env_logger::try_init().unwrap_or_default();
@@ -500,6 +1228,47 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn should_fail_on_racing_first_saves() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("should_fail_on_racing_first_saves")?;
+        let some_doc = ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "First".to_string(),
+        };
+        let mut other_doc = ADocument {
+            name: "Second".to_string(),
+            ..some_doc.clone()
+        };
+        // So that neither save waits on registering the entity.
+        pool.get()?.save(&mut ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Unrelated".to_string(),
+        })?;
+
+        let first = pool.get()?;
+        let second = pool.get()?;
+        let t = first.connection.transaction()?;
+        first.save_in_xact(&t, &mut some_doc.clone())?;
+        // Blocks on the first insert, until that commits.
+        let racer = thread::spawn(move || second.save(&mut other_doc));
+        thread::sleep(Duration::from_millis(100));
+        t.commit()?;
+
+        let err = racer
+            .join()
+            .expect("no panic")
+            .expect_err("save should fail");
+        info!("Save failed with: {:?}", err);
+        assert_eq!(
+            err.root_cause().downcast_ref::<ConcurrencyError>(),
+            Some(&ConcurrencyError),
+            "Error: {:?}",
+            err
+        );
+        Ok(())
+    }
+
     #[test]
     fn should_fail_on_overwrite_with_new() -> Result<(), Error> {
         env_logger::try_init().unwrap_or_default();
@@ -674,4 +1443,456 @@ mod test {
         assert_eq!(Some(some_doc.name), loaded.map(|d| d.name));
         Ok(())
     }
+
+    #[test]
+    fn supervisor_should_process_all_registered_prefixes() -> Result<(), Error> {
+        use crate::supervisor::Supervisor;
+        use std::cell::Cell;
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("supervisor_should_process_all_registered_prefixes")?;
+        let mut docs = pool.get()?;
+
+        for _ in 0..3 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            docs.save(&mut ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox: mbox.clone(),
+            })?;
+            docs.save(&mut ChattyDoc2 {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            })?;
+        }
+
+        let chatty = Cell::new(0);
+        let chatty2 = Cell::new(0);
+        let mut supervisor = Supervisor::new();
+        supervisor
+            .register(|doc: &mut ChattyDoc| {
+                while let Some(AMessage) = doc.mbox.take_one() {
+                    chatty.set(chatty.get() + 1);
+                }
+                Ok(())
+            })
+            .register(|doc: &mut ChattyDoc2| {
+                while let Some(AMessage) = doc.mbox.take_one() {
+                    chatty2.set(chatty2.get() + 1);
+                }
+                Ok(())
+            });
+
+        supervisor.listen(&mut *docs)?;
+        while supervisor.poll(&mut *docs).processed > 0 {}
+
+        assert_eq!((chatty.get(), chatty2.get()), (3, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn supervisor_should_only_load_notified_documents() -> Result<(), Error> {
+        use crate::supervisor::Supervisor;
+        use std::cell::Cell;
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("supervisor_should_only_load_notified_documents")?;
+        let mut docs = pool.get()?;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            let mut doc = ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            };
+            docs.save(&mut doc)?;
+            ids.push(doc.meta.id);
+        }
+
+        let chatty = Cell::new(0);
+        let mut supervisor = Supervisor::new();
+        supervisor.register(|doc: &mut ChattyDoc| {
+            while let Some(AMessage) = doc.mbox.take_one() {
+                chatty.set(chatty.get() + 1);
+            }
+            Ok(())
+        });
+        let notified = |payload: String| Notification {
+            channel: ChattyDoc::PREFIX.to_string(),
+            payload,
+        };
+
+        let pass = supervisor.poll_notified(&mut *docs, &[notified(ids[0].to_string())]);
+        assert_eq!((pass.processed, chatty.get()), (1, 1));
+
+        // Other prefixes' notifications are none of this handler's business.
+        let other = Notification {
+            channel: "other".to_string(),
+            payload: ids[1].to_string(),
+        };
+        let pass = supervisor.poll_notified(&mut *docs, &[other]);
+        assert_eq!((pass.processed, chatty.get()), (0, 1));
+
+        // We can't tell which document was meant, so look at them all.
+        let pass = supervisor.poll_notified(&mut *docs, &[notified("garbage".to_string())]);
+        assert_eq!((pass.processed, chatty.get()), (1, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn supervisor_should_isolate_failing_handlers() -> Result<(), Error> {
+        use crate::supervisor::Supervisor;
+        use std::cell::Cell;
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("supervisor_should_isolate_failing_handlers")?;
+        let mut docs = pool.get()?;
+
+        let mut mbox = MailBox::empty();
+        mbox.send(AMessage);
+        docs.save(&mut ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox: mbox.clone(),
+        })?;
+        let mut failing = ChattyDoc2 {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox,
+        };
+        docs.save(&mut failing)?;
+
+        let chatty = Cell::new(0);
+        let mut supervisor = Supervisor::new();
+        supervisor
+            .register(|doc: &mut ChattyDoc| {
+                while let Some(AMessage) = doc.mbox.take_one() {
+                    chatty.set(chatty.get() + 1);
+                }
+                Ok(())
+            })
+            .register(|_: &mut ChattyDoc2| -> Result<(), Error> { panic!("Boom") });
+
+        let pass = supervisor.poll(&mut *docs);
+
+        assert_eq!((pass.processed, pass.failed), (1, 1));
+        assert_eq!(chatty.get(), 1);
+        let reloaded = docs.load(&failing.meta.id)?.expect("failing doc");
+        assert_eq!(reloaded.meta.version, failing.meta.version);
+        Ok(())
+    }
+
+    #[test]
+    fn process_pending_should_commit_rest_of_batch_on_failure() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("process_pending_should_commit_rest_of_batch_on_failure")?;
+        let mut docs = pool.get()?;
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            let mut doc = ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            };
+            docs.save(&mut doc)?;
+            ids.push(doc.meta.id);
+        }
+        let poison = ids[1];
+
+        let err = docs
+            .process_pending(&|doc: &mut ChattyDoc| {
+                if doc.meta.id == poison {
+                    return Err(anyhow::anyhow!("Poisoned document"));
+                }
+                doc.mbox.take_one();
+                Ok(())
+            })
+            .expect_err("process_pending should fail");
+        info!("Processing failed with: {:?}", err);
+
+        for id in ids.iter() {
+            let doc = docs.load(id)?.expect("document");
+            assert_eq!(
+                doc.mbox.outgoing.is_empty(),
+                *id != poison,
+                "Document {} outbox: {:?}",
+                id,
+                doc.mbox.outgoing
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn process_pending_should_claim_at_most_a_batch() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("process_pending_should_claim_at_most_a_batch")?;
+        let mut docs = pool.get()?;
+
+        for _ in 0..6 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            docs.save(&mut ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            })?;
+        }
+
+        let drain = |doc: &mut ChattyDoc| {
+            doc.mbox.take_one();
+            Ok(())
+        };
+        assert_eq!(docs.process_pending(&drain)?, 4);
+        assert_eq!(docs.process_pending(&drain)?, 2);
+        assert_eq!(docs.process_pending(&drain)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn outbox_status_should_report_pending_documents_by_prefix() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_status_should_report_pending_documents_by_prefix")?;
+        let docs = pool.get()?;
+
+        for _ in 0..3 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            docs.save(&mut ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            })?;
+        }
+        docs.save(&mut ChattyDoc2 {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox: MailBox::empty(),
+        })?;
+        docs.save(&mut ADocument {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            name: "Quiet".to_string(),
+        })?;
+
+        let statuses = docs.outbox_status()?;
+        info!("Outbox status: {:?}", statuses);
+
+        assert_eq!(
+            statuses
+                .iter()
+                .map(|s| (s.prefix.as_str(), s.pending))
+                .collect::<Vec<_>>(),
+            vec![("chatty", 3)]
+        );
+        assert!(statuses[0].oldest_pending_age.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn outbox_status_should_track_age_from_first_pending_save() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("outbox_status_should_track_age_from_first_pending_save")?;
+        let docs = pool.get()?;
+
+        let mut mbox = MailBox::empty();
+        mbox.send(AMessage);
+        let mut doc = ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox,
+        };
+        docs.save(&mut doc)?;
+        std::thread::sleep(Duration::from_millis(50));
+        docs.save(&mut doc)?;
+
+        let statuses = docs.outbox_status()?;
+        let age = statuses[0].oldest_pending_age.expect("age");
+        assert!(age >= Duration::from_millis(50), "Age: {:?}", age);
+
+        doc.mbox.take_one();
+        docs.save(&mut doc)?;
+        assert_eq!(docs.outbox_status()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn process_notified_should_only_handle_named_documents() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("process_notified_should_only_handle_named_documents")?;
+        let mut docs = pool.get()?;
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            let mut doc = ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            };
+            docs.save(&mut doc)?;
+            ids.push(doc.meta.id);
+        }
+
+        let drain = |doc: &mut ChattyDoc| {
+            doc.mbox.take_one();
+            Ok(())
+        };
+        assert_eq!(docs.process_notified(&ids[0..1], &drain)?, 1);
+        assert_eq!(docs.process_notified(&ids[0..1], &drain)?, 0);
+
+        let remaining = docs.outbox_status()?;
+        assert_eq!(remaining[0].pending, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn subscribe_should_survive_losing_connection() -> Result<(), Error> {
+        use std::sync::mpsc;
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("subscribe_should_survive_losing_connection")?;
+
+        let mut subscriber = pool.get()?;
+        let pid: i32 = subscriber
+            .get_ref()
+            .query("SELECT pg_backend_pid()", &[])?
+            .get(0)
+            .get(0);
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            subscriber.subscribe(|doc: &mut ChattyDoc| {
+                while let Some(AMessage) = doc.mbox.take_one() {
+                    tx.send(doc.meta.id).expect("send");
+                }
+                Ok(())
+            })
+        });
+
+        let docs = pool.get()?;
+        let send = || -> Result<Id<ChattyDoc>, Error> {
+            let mut mbox = MailBox::empty();
+            mbox.send(AMessage);
+            let mut doc = ChattyDoc {
+                meta: DocMeta::new_with_id(IDGEN.generate()),
+                mbox,
+            };
+            docs.save(&mut doc)?;
+            Ok(doc.meta.id)
+        };
+
+        let first = send()?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5))?, first);
+        while !docs.outbox_status()?.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        docs.get_ref()
+            .execute("SELECT pg_terminate_backend($1)", &[&pid])?;
+
+        let second = send()?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5))?, second);
+        Ok(())
+    }
+
+    #[test]
+    fn workers_should_record_last_claimed_document() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("workers_should_record_last_claimed_document")?;
+        let mut docs = pool.get()?;
+        let lease = WorkerLease::for_current_process(&IDGEN);
+        docs.lease = Some(lease.clone());
+
+        let mut mbox = MailBox::empty();
+        mbox.send(AMessage);
+        let mut doc = ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox,
+        };
+        docs.save(&mut doc)?;
+
+        docs.process_pending(&|doc: &mut ChattyDoc| {
+            doc.mbox.take_one();
+            Ok(())
+        })?;
+
+        let workers = docs.workers()?;
+        info!("Workers: {:?}", workers);
+        assert_eq!(workers.len(), 1);
+        assert_eq!(workers[0].worker_id, lease.worker_id);
+        assert_eq!(workers[0].pid, lease.pid);
+        assert_eq!(workers[0].prefix, "chatty");
+        assert_eq!(workers[0].last_claimed, Some(doc.meta.id.to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn process_pending_should_survive_failed_heartbeat() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("process_pending_should_survive_failed_heartbeat")?;
+        let mut docs = pool.get()?;
+        docs.lease = Some(WorkerLease::for_current_process(&IDGEN));
+        docs.get_ref().execute("DROP TABLE workers", &[])?;
+
+        let mut mbox = MailBox::empty();
+        mbox.send(AMessage);
+        let mut doc = ChattyDoc {
+            meta: DocMeta::new_with_id(IDGEN.generate()),
+            mbox,
+        };
+        docs.save(&mut doc)?;
+
+        let handled = docs.process_pending(&|doc: &mut ChattyDoc| {
+            doc.mbox.take_one();
+            Ok(())
+        })?;
+
+        assert_eq!(handled, 1);
+        assert_eq!(docs.outbox_status()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn pending_config_should_need_lease_to_outlast_notification_timeout() {
+        let config = |notification_timeout, lease_ttl| PendingConfig {
+            notification_timeout: Duration::from_secs(notification_timeout),
+            lease_ttl: Duration::from_secs(lease_ttl),
+            ..PendingConfig::default()
+        };
+
+        assert!(PendingConfig::default().check().is_ok());
+        assert!(config(60, 180).check().is_ok());
+        assert!(config(60, 60).check().is_err());
+        assert!(config(60, 90).check().is_err());
+    }
+
+    #[test]
+    fn workers_should_expire_stale_entries() -> Result<(), Error> {
+        env_logger::try_init().unwrap_or_default();
+        let pool = pool("workers_should_expire_stale_entries")?;
+        let mut docs = pool.get()?;
+
+        let stale = WorkerLease::for_current_process(&IDGEN);
+        docs.lease = Some(stale.clone());
+        StoragePending::heartbeat(&mut *docs, "chatty")?;
+        docs.get_ref().execute(
+            "UPDATE workers SET heartbeat_at = now() - interval '1 hour'",
+            &[],
+        )?;
+        assert_eq!(docs.workers()?, vec![]);
+
+        let live = WorkerLease::for_current_process(&IDGEN);
+        docs.lease = Some(live.clone());
+        docs.heartbeats.clear();
+        StoragePending::heartbeat(&mut *docs, "chatty")?;
+
+        let remaining: i64 = docs
+            .get_ref()
+            .query("SELECT count(*) FROM workers", &[])?
+            .get(0)
+            .get(0);
+        assert_eq!(remaining, 1);
+        assert_eq!(
+            docs.workers()?
+                .into_iter()
+                .map(|w| w.worker_id)
+                .collect::<Vec<_>>(),
+            vec![live.worker_id]
+        );
+        Ok(())
+    }
 }
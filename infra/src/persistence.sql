@@ -60,4 +60,85 @@ SELECT apply_migration(text '0005 Add listen helper', text $migration$
     create function do_listen(channel text) returns void AS $fn$
         BEGIN EXECUTE 'LISTEN ' || quote_ident(channel); END
     $fn$ LANGUAGE 'plpgsql';
-$migration$);
\ No newline at end of file
+$migration$);
+
+SELECT apply_migration(text '0006 Track when documents became pending', text $$
+    ALTER TABLE documents ADD COLUMN pending_since timestamptz;
+    UPDATE documents SET pending_since = now()
+        WHERE jsonb_array_length(body -> '_outgoing') > 0;
+$$);
+
+SELECT apply_migration(text '0007 Add worker registry', text $$
+    CREATE TABLE workers (
+        worker_id TEXT NOT NULL,
+        prefix TEXT NOT NULL,
+        host TEXT NOT NULL,
+        pid INTEGER NOT NULL,
+        started_at timestamptz NOT NULL,
+        heartbeat_at timestamptz NOT NULL,
+        last_claimed TEXT,
+        last_claimed_at timestamptz,
+        PRIMARY KEY (worker_id, prefix)
+    );
+$$);
+
+SELECT apply_migration(text '0008 Store ids as entity code and binary id', text $migration$
+    CREATE TABLE entities (
+        code smallserial PRIMARY KEY,
+        prefix TEXT NOT NULL UNIQUE
+    );
+    CREATE FUNCTION base32hex_decode(src text) RETURNS bytea AS $fn$
+        DECLARE
+            alphabet CONSTANT text := '0123456789abcdefghijklmnopqrstuv';
+            bits varbit := B'';
+            hex text := '';
+        BEGIN
+            FOR i IN 1..length(src) LOOP
+                bits := bits || (strpos(alphabet, substr(src, i, 1)) - 1)::bit(5);
+            END LOOP;
+            FOR i IN 0..3 LOOP
+                hex := hex || lpad(to_hex(substring(bits FROM i * 32 + 1 FOR 32)::bit(32)::int), 8, '0');
+            END LOOP;
+            RETURN decode(hex, 'hex');
+        END
+    $fn$ LANGUAGE 'plpgsql' IMMUTABLE STRICT;
+    INSERT INTO entities (prefix)
+        SELECT DISTINCT split_part(id, '.', 1) FROM documents ORDER BY 1;
+    ALTER TABLE documents
+        ADD COLUMN entity smallint REFERENCES entities (code),
+        ADD COLUMN uid bytea;
+    UPDATE documents d
+        SET entity = e.code, uid = base32hex_decode(split_part(d.id, '.', 2))
+        FROM entities e
+        WHERE e.prefix = split_part(d.id, '.', 1);
+    ALTER TABLE documents
+        DROP CONSTRAINT id_coherence,
+        DROP CONSTRAINT documents_pkey,
+        DROP COLUMN id,
+        ALTER COLUMN entity SET NOT NULL,
+        ALTER COLUMN uid SET NOT NULL,
+        ADD PRIMARY KEY (entity, uid);
+    DROP INDEX IF EXISTS documents_jsonb_array_length_idx;
+    CREATE INDEX documents_pending_idx ON documents (entity)
+        WHERE jsonb_array_length(body -> '_outgoing') > 0;
+$migration$);
+
+SELECT apply_migration(text '0009 Check body ids against keys', text $migration$
+    ALTER TABLE documents ADD CONSTRAINT uid_coherence
+        CHECK ((body ->> '_id') IS NOT NULL
+            AND uid = base32hex_decode(split_part(body ->> '_id', '.', 2)));
+    CREATE FUNCTION check_entity_coherence() RETURNS trigger AS $fn$
+        BEGIN
+            IF NOT EXISTS (SELECT 1 FROM entities
+                    WHERE code = NEW.entity
+                    AND prefix = split_part(NEW.body ->> '_id', '.', 1)) THEN
+                RAISE EXCEPTION 'Document % is not of entity %',
+                    NEW.body ->> '_id', NEW.entity
+                    USING ERRCODE = 'check_violation';
+            END IF;
+            RETURN NEW;
+        END
+    $fn$ LANGUAGE 'plpgsql';
+    CREATE TRIGGER entity_coherence BEFORE INSERT OR UPDATE ON documents
+        FOR EACH ROW EXECUTE PROCEDURE check_entity_coherence();
+$migration$);
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Error};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::ids::{AnyId, Entity};
+use crate::persistence::Storage;
+
+/// Maps the prefixes of known entity types to a typed load and a
+/// pretty-printer, so that an id of any known type can be looked up without
+/// knowing its type up front.
+pub struct Registry<S> {
+    entries: BTreeMap<&'static str, Show<S>>,
+}
+
+type Show<S> = Box<dyn Fn(&S, &AnyId) -> Result<Option<String>, Error> + Send + Sync>;
+
+impl<S: Storage> Registry<S> {
+    pub fn new() -> Self {
+        let entries = BTreeMap::new();
+        Registry { entries }
+    }
+
+    /// Registers `D`, printing documents as pretty JSON.
+    pub fn register<D: DeserializeOwned + Serialize + Entity + 'static>(&mut self) -> &mut Self {
+        self.register_with::<D, _>(|doc| {
+            serde_json::to_string_pretty(doc).unwrap_or_else(|e| format!("<{}>", e))
+        })
+    }
+
+    pub fn register_with<
+        D: DeserializeOwned + Entity + 'static,
+        F: Fn(&D) -> String + Send + Sync + 'static,
+    >(
+        &mut self,
+        printer: F,
+    ) -> &mut Self {
+        let show = Box::new(move |storage: &S, id: &AnyId| {
+            let id = id
+                .typed::<D>()
+                .ok_or_else(|| anyhow!("Not a {} id: {}", D::PREFIX, id))?;
+            Ok(storage.load(&id)?.map(|doc| printer(&doc)))
+        });
+        self.entries.insert(D::PREFIX, show);
+        self
+    }
+
+    pub fn prefixes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries.keys().cloned()
+    }
+
+    pub fn is_known(&self, id: &AnyId) -> bool {
+        self.entries.contains_key(&*id.prefix)
+    }
+
+    /// Parses an id, verifying that its prefix belongs to a registered
+    /// entity type.
+    pub fn parse(&self, src: &str) -> Result<AnyId, Error> {
+        let id = src.parse::<AnyId>()?;
+        if !self.is_known(&id) {
+            return Err(anyhow!("Unknown entity prefix: {:?}", id.prefix));
+        }
+        Ok(id)
+    }
+
+    /// Loads the document with the given id and pretty-prints it, or returns
+    /// `None` if there is no such document.
+    pub fn show(&self, storage: &S, id: &AnyId) -> Result<Option<String>, Error> {
+        let show = self
+            .entries
+            .get(&*id.prefix)
+            .ok_or_else(|| anyhow!("Unknown entity prefix: {:?}", id.prefix))?;
+        show(storage, id)
+    }
+}
+
+impl<S: Storage> Default for Registry<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ops::{Bound, RangeBounds};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::documents::{DocMeta, HasMeta};
+    use crate::ids::{Id, IdGen};
+
+    #[derive(Debug, Default)]
+    struct MemStorage {
+        docs: RefCell<HashMap<String, serde_json::Value>>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Cat {
+        #[serde(flatten)]
+        meta: DocMeta<Cat>,
+        name: String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Dog {
+        #[serde(flatten)]
+        meta: DocMeta<Dog>,
+        name: String,
+    }
+
+    impl Entity for Cat {
+        const PREFIX: &'static str = "cat";
+    }
+    impl HasMeta for Cat {
+        fn meta(&self) -> &DocMeta<Self> {
+            &self.meta
+        }
+        fn meta_mut(&mut self) -> &mut DocMeta<Self> {
+            &mut self.meta
+        }
+    }
+
+    impl Entity for Dog {
+        const PREFIX: &'static str = "dog";
+    }
+    impl HasMeta for Dog {
+        fn meta(&self) -> &DocMeta<Self> {
+            &self.meta
+        }
+        fn meta_mut(&mut self) -> &mut DocMeta<Self> {
+            &mut self.meta
+        }
+    }
+
+    impl Storage for MemStorage {
+        fn load<D: DeserializeOwned + Entity>(&self, id: &Id<D>) -> Result<Option<D>, Error> {
+            self.docs
+                .borrow()
+                .get(&id.to_string())
+                .map(|v| Ok(serde_json::from_value(v.clone())?))
+                .transpose()
+        }
+        fn load_range<D: DeserializeOwned + Entity>(
+            &self,
+            from: Bound<&Id<D>>,
+            to: Bound<&Id<D>>,
+            limit: usize,
+        ) -> Result<Vec<D>, Error> {
+            let docs = self.docs.borrow();
+            let mut ids = docs
+                .keys()
+                .filter_map(|k| k.parse::<Id<D>>().ok())
+                .filter(|id| (from, to).contains(id))
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.into_iter()
+                .take(limit)
+                .map(|id| Ok(serde_json::from_value(docs[&id.to_string()].clone())?))
+                .collect()
+        }
+        fn save<D: Serialize + Entity + HasMeta>(&self, document: &mut D) -> Result<(), Error> {
+            let id = document.meta().id.to_string();
+            let value = serde_json::to_value(&*document)?;
+            self.docs.borrow_mut().insert(id, value);
+            Ok(())
+        }
+    }
+
+    fn registry() -> Registry<MemStorage> {
+        let mut registry = Registry::new();
+        registry
+            .register::<Cat>()
+            .register_with::<Dog, _>(|d| format!("Dog called {}", d.name));
+        registry
+    }
+
+    #[test]
+    fn should_list_registered_prefixes() {
+        assert_eq!(
+            registry().prefixes().collect::<Vec<_>>(),
+            vec!["cat", "dog"]
+        );
+    }
+
+    #[test]
+    fn parse_should_reject_unknown_prefix() {
+        let id = AnyId::from(IdGen::new().generate::<Cat>());
+        let unknown = format!("mouse.{}", id.id);
+
+        assert!(registry().parse(&id.to_string()).is_ok());
+        assert!(registry().parse(&unknown).is_err());
+    }
+
+    #[test]
+    fn show_should_dispatch_by_prefix() -> Result<(), Error> {
+        let storage = MemStorage::default();
+        let idgen = IdGen::new();
+        let mut cat = Cat {
+            meta: DocMeta::new_with_id(idgen.generate()),
+            name: "Tiddles".to_string(),
+        };
+        let mut dog = Dog {
+            meta: DocMeta::new_with_id(idgen.generate()),
+            name: "Rex".to_string(),
+        };
+        storage.save(&mut cat)?;
+        storage.save(&mut dog)?;
+
+        let registry = registry();
+        let shown_cat = registry.show(&storage, &registry.parse(&cat.meta.id.to_string())?)?;
+        let shown_dog = registry.show(&storage, &registry.parse(&dog.meta.id.to_string())?)?;
+
+        assert!(
+            shown_cat.as_ref().map(|s| s.contains("Tiddles")) == Some(true),
+            "Shown cat: {:?}",
+            shown_cat
+        );
+        assert_eq!(shown_dog, Some("Dog called Rex".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn show_should_return_none_for_missing_document() -> Result<(), Error> {
+        let storage = MemStorage::default();
+        let id = AnyId::from(IdGen::new().generate::<Cat>());
+
+        assert_eq!(registry().show(&storage, &id)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use log::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::documents::HasMeta;
+use crate::ids::{Entity, Id};
+use crate::persistence::{Notification, StoragePending};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Sockets can't wait for no time at all.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// Runs handlers for pending documents of several entity types over a
+/// single connection.
+///
+/// Each pass visits every handler in turn, starting from a different one
+/// each time, so a backlog for one prefix cannot starve the others. A
+/// handler that fails (or panics) is restarted after an exponential backoff,
+/// without interrupting the remaining handlers.
+///
+/// As with `StoragePending::subscribe`, documents named by notifications are
+/// loaded directly, and all pending documents are only scanned for once per
+/// notification timeout.
+pub struct Supervisor<'a, C> {
+    handlers: Vec<Registration<'a, C>>,
+    next: usize,
+}
+
+/// Processes the given notification payloads, or scans for pending
+/// documents when there are none.
+type Process<'a, C> = Box<dyn Fn(&mut C, Option<&[&str]>) -> Result<usize, Error> + 'a>;
+
+struct Registration<'a, C> {
+    prefix: &'static str,
+    process: Process<'a, C>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Pass {
+    pub processed: usize,
+    pub failed: usize,
+}
+
+impl<'a, C: StoragePending> Supervisor<'a, C> {
+    pub fn new() -> Self {
+        let handlers = Vec::new();
+        let next = 0;
+        Supervisor { handlers, next }
+    }
+
+    pub fn register<
+        D: DeserializeOwned + Serialize + Entity + HasMeta + 'a,
+        F: Fn(&mut D) -> Result<(), Error> + 'a,
+    >(
+        &mut self,
+        handler: F,
+    ) -> &mut Self {
+        let process = Box::new(move |conn: &mut C, payloads: Option<&[&str]>| {
+            let payloads = match payloads {
+                Some(payloads) => payloads,
+                None => return conn.process_pending(&handler),
+            };
+            let mut ids = Vec::new();
+            for payload in payloads {
+                match payload.parse::<Id<D>>() {
+                    Ok(id) => ids.push(id),
+                    Err(e) => {
+                        warn!("Unparseable notification payload {:?}: {:?}", payload, e);
+                        return conn.process_pending(&handler);
+                    }
+                }
+            }
+            ids.sort();
+            ids.dedup();
+            conn.process_notified(&ids, &handler)
+        });
+        self.handlers.push(Registration {
+            prefix: D::PREFIX,
+            process,
+            failures: 0,
+            retry_at: None,
+        });
+        self
+    }
+
+    pub fn prefixes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.iter().map(|h| h.prefix)
+    }
+
+    pub fn listen(&self, conn: &mut C) -> Result<(), Error> {
+        for prefix in self.prefixes() {
+            conn.listen(prefix)?;
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat(&self, conn: &mut C) -> Result<(), Error> {
+        for prefix in self.prefixes() {
+            conn.heartbeat(prefix)?;
+        }
+        Ok(())
+    }
+
+    /// Gives each handler that is not currently backing off one chance to
+    /// claim and process work.
+    pub fn poll(&mut self, conn: &mut C) -> Pass {
+        self.pass(conn, None)
+    }
+
+    /// As `poll`, but only for the documents named by the notifications.
+    /// Handlers with no notifications are skipped.
+    pub fn poll_notified(&mut self, conn: &mut C, notifications: &[Notification]) -> Pass {
+        self.pass(conn, Some(notifications))
+    }
+
+    fn pass(&mut self, conn: &mut C, notifications: Option<&[Notification]>) -> Pass {
+        let now = Instant::now();
+        let mut pass = Pass::default();
+        let nhandlers = self.handlers.len();
+
+        for i in 0..nhandlers {
+            let reg = &mut self.handlers[(self.next + i) % nhandlers];
+            if reg.retry_at.map(|t| t > now).unwrap_or(false) {
+                continue;
+            }
+            let payloads = notifications.map(|notifs| {
+                notifs
+                    .iter()
+                    .filter(|n| n.channel == reg.prefix)
+                    .map(|n| &*n.payload)
+                    .collect::<Vec<_>>()
+            });
+            if payloads.as_ref().map(|p| p.is_empty()).unwrap_or(false) {
+                continue;
+            }
+
+            let process = &reg.process;
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| process(conn, payloads.as_deref())))
+                    .unwrap_or_else(|p| Err(panic_error(p)));
+
+            match result {
+                Ok(n) => {
+                    if reg.failures > 0 {
+                        info!("Handler for {} recovered", reg.prefix);
+                    }
+                    reg.failures = 0;
+                    reg.retry_at = None;
+                    pass.processed += n;
+                }
+                Err(e) => {
+                    reg.failures += 1;
+                    let delay = backoff(reg.failures);
+                    error!(
+                        "Handler for {} failed ({} times); restarting in {:?}: {:?}",
+                        reg.prefix, reg.failures, delay, e
+                    );
+                    reg.retry_at = Some(now + delay);
+                    pass.failed += 1;
+                }
+            }
+        }
+
+        if nhandlers > 0 {
+            self.next = (self.next + 1) % nhandlers;
+        }
+
+        pass
+    }
+
+    /// Processes pending documents indefinitely. After a handler failure,
+    /// or if the connection is lost, the connection is returned to the pool
+    /// and a fresh one is acquired, backing off while none can be, before
+    /// scanning for anything missed in the meantime.
+    pub fn run<M: r2d2::ManageConnection<Connection = C>>(
+        &mut self,
+        pool: &r2d2::Pool<M>,
+    ) -> Result<(), Error> {
+        info!(
+            "Supervising handlers for: {:?}",
+            self.prefixes().collect::<Vec<_>>()
+        );
+        let mut reconnects = 0;
+        loop {
+            let mut conn = match pool.get().map_err(Error::from).and_then(|mut conn| {
+                self.listen(&mut conn)?;
+                Ok(conn)
+            }) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    reconnects += 1;
+                    let delay = backoff(reconnects);
+                    warn!("Error connecting; retrying in {:?}: {:?}", delay, e);
+                    thread::sleep(delay);
+                    continue;
+                }
+            };
+            reconnects = 0;
+
+            if let Err(e) = self.run_connected(&mut conn) {
+                warn!("Lost connection; reconnecting: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns once a handler fails, or the connection does.
+    fn run_connected(&mut self, conn: &mut C) -> Result<(), Error> {
+        let scan_interval = conn.notification_timeout();
+        let mut last_scan: Option<Instant> = None;
+        loop {
+            let now = Instant::now();
+            let scan_due = last_scan
+                .map(|t| now.duration_since(t) >= scan_interval)
+                .unwrap_or(true);
+            if scan_due || self.retry_due(now) {
+                let pass = self.poll(conn);
+                debug!("Completed scan: {:?}", pass);
+                if pass.failed > 0 {
+                    return Ok(());
+                }
+                if pass.processed > 0 {
+                    continue;
+                }
+                if scan_due {
+                    last_scan = Some(now);
+                }
+            }
+
+            // Losing our registry entry is no reason to stop working.
+            if let Err(e) = self.heartbeat(conn) {
+                warn!("Error sending heartbeat: {:?}", e);
+            }
+
+            let next_scan = last_scan
+                .map(|t| scan_interval.checked_sub(t.elapsed()).unwrap_or_default())
+                .unwrap_or_default();
+            let timeout = cmp::max(self.wait_timeout(next_scan), MIN_WAIT);
+            let mut notifications = Vec::new();
+            notifications.extend(conn.await_notification(timeout)?);
+            notifications.extend(conn.buffered_notifications()?);
+            if notifications.is_empty() {
+                continue;
+            }
+            debug!("Found notifications: {:?}", notifications);
+
+            let pass = self.poll_notified(conn, &notifications);
+            debug!("Completed pass: {:?}", pass);
+            if pass.failed > 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn retry_due(&self, now: Instant) -> bool {
+        self.handlers
+            .iter()
+            .filter_map(|h| h.retry_at)
+            .any(|t| t <= now)
+    }
+
+    fn wait_timeout(&self, notification_timeout: Duration) -> Duration {
+        let now = Instant::now();
+        self.handlers
+            .iter()
+            .filter_map(|h| h.retry_at)
+            .map(|t| t.saturating_duration_since(now))
+            .fold(notification_timeout, cmp::min)
+    }
+}
+
+impl<'a, C: StoragePending> Default for Supervisor<'a, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long to wait before retrying after the given number of consecutive
+/// failures.
+pub fn backoff(failures: u32) -> Duration {
+    let factor = 1u32 << cmp::min(failures.saturating_sub(1), 16);
+    cmp::min(INITIAL_BACKOFF * factor, MAX_BACKOFF)
+}
+
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> Error {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        anyhow!("Handler panicked: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        anyhow!("Handler panicked: {}", s)
+    } else {
+        anyhow!("Handler panicked")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_should_grow_exponentially() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(3), INITIAL_BACKOFF * 4);
+    }
+
+    #[test]
+    fn backoff_should_be_bounded() {
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+}
@@ -7,9 +7,23 @@ use anyhow::Error;
 use data_encoding::BASE32_DNSSEC;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::ids::{Id, IdGen, IdParseError, ENCODED_BARE_ID_LEN};
+use crate::ids::{Id, IdGen, IdParseError, CHECKED_BARE_ID_LEN, ENCODED_BARE_ID_LEN};
+
+/// Key material for hashed ids. Deployments can derive one from a secret,
+/// so that hashed ids cannot be predicted from their inputs.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct IdKey {
+    k0: u64,
+    k1: u64,
+}
+
+/// Ids hold nanoseconds since 1970 in 64 bits, so only times from then
+/// until about 2554.
+#[derive(Debug, Clone, PartialEq, Eq, err_derive::Error)]
+#[error(display = "{:?} is outside the times ids can hold (1970 to 2554)", _0)]
+pub struct TimeOutOfRange(pub SystemTime);
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default)]
 pub struct UntypedId {
     // Unix time in ms
     pub(crate) stamp: u64,
@@ -18,21 +32,44 @@ pub struct UntypedId {
 
 impl IdGen {
     pub fn untyped(&self) -> UntypedId {
-        let stamp_epoch = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("now");
-        let stamp_s: u64 = stamp_epoch
-            .as_secs()
-            .checked_mul(1000 * 1000 * 1000)
-            .expect("secs * 1000,000,000");
-        let stamp_ms: u64 = stamp_epoch.subsec_nanos().into();
-        let stamp = stamp_s + stamp_ms;
-        let random = rand::random();
-
-        UntypedId { random, stamp }
+        let stamp = stamp_of(self.clock.now()).expect("clock within id range");
+        let random = self.random.next_u64();
+
+        match self.last {
+            Some(ref last) => {
+                let mut last = last.lock().unwrap_or_else(|e| e.into_inner());
+                next_monotonic(&mut last, stamp, random)
+            }
+            None => UntypedId { random, stamp },
+        }
     }
 }
 
+fn stamp_of(t: SystemTime) -> Result<u64, TimeOutOfRange> {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .and_then(|d| d.as_nanos().try_into().ok())
+        .ok_or(TimeOutOfRange(t))
+}
+
+fn next_monotonic(last: &mut UntypedId, stamp: u64, random: u64) -> UntypedId {
+    let next = if stamp > last.stamp {
+        UntypedId { stamp, random }
+    } else if let Some(random) = last.random.checked_add(1) {
+        UntypedId {
+            stamp: last.stamp,
+            random,
+        }
+    } else {
+        UntypedId {
+            stamp: last.stamp + 1,
+            random,
+        }
+    };
+    *last = next;
+    next
+}
+
 impl UntypedId {
     pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
         let stamp = u64::from_be_bytes(bytes[0..8].try_into().expect("stamp bytes"));
@@ -51,17 +88,42 @@ impl UntypedId {
     /// Returns a id nominally at time zero, but with a random portion derived
     /// from the given entity.
     pub fn hashed<H: Hash>(entity: H) -> Self {
+        Self::hashed_with(&IdKey::default(), entity)
+    }
+
+    /// As `hashed`, but keyed. With the default key, this is the same as
+    /// `hashed`.
+    pub fn hashed_with<H: Hash>(key: &IdKey, entity: H) -> Self {
         let stamp_limit_ns = (1 << 30) * 1_000_000_000;
-        let raw_stamp = sip_hash(0, 1, &entity);
+        let raw_stamp = sip_hash(key.k0, key.k1 ^ 1, &entity);
         // Rescale the value from 0..u64::max_value() to 0..stamp_limit_ns;
         let stamp = ((u128::from(raw_stamp) * stamp_limit_ns) >> 64)
             .try_into()
             .unwrap();
-        let random = sip_hash(0, 0, &entity);
+        let random = sip_hash(key.k0, key.k1, &entity);
 
         UntypedId { stamp, random }
     }
 
+    /// Returns an id for a related entity, with the same timestamp as this
+    /// one, so they sort together. The random portion is a hash of the
+    /// discriminator, keyed by this id, so distinct discriminators under the
+    /// same parent yield distinct ids.
+    pub fn derive<H: Hash>(&self, discriminator: H) -> Self {
+        let random = sip_hash(self.stamp, self.random, &discriminator);
+        UntypedId {
+            stamp: self.stamp,
+            random,
+        }
+    }
+
+    /// Returns the id for the given time and random portion, eg: with a
+    /// random portion of zero or `u64::MAX` to bound a range of ids.
+    pub fn at(t: SystemTime, random: u64) -> Result<Self, TimeOutOfRange> {
+        let stamp = stamp_of(t)?;
+        Ok(UntypedId { stamp, random })
+    }
+
     pub fn typed<T>(&self) -> Id<T> {
         Id::from_untyped(*self)
     }
@@ -75,40 +137,93 @@ impl UntypedId {
     }
 }
 
+impl IdKey {
+    pub fn from_secret(secret: &str) -> Self {
+        let k0 = sip_hash(0, 2, &secret);
+        let k1 = sip_hash(0, 3, &secret);
+        IdKey { k0, k1 }
+    }
+}
+
 fn sip_hash<H: Hash>(k0: u64, k1: u64, entity: &H) -> u64 {
     let mut h = siphasher::sip::SipHasher24::new_with_keys(k0, k1);
     entity.hash(&mut h);
     h.finish()
 }
 
+// Symbols for the check digit, which is the encoded id modulo 37. As 37 is
+// prime and larger than the base, this catches any single substituted
+// symbol, and any transposition of adjacent symbols.
+const CHECK_SYMBOLS: &[u8; 37] = b"0123456789abcdefghijklmnopqrstuvwxyz_";
+const ENCODED_SYMBOLS: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+fn check_symbol(encoded: &[u8]) -> Option<u8> {
+    let mut acc = 0u32;
+    for &c in encoded {
+        let digit = ENCODED_SYMBOLS.iter().position(|&d| d == c)? as u32;
+        acc = (acc * 32 + digit) % 37;
+    }
+    Some(CHECK_SYMBOLS[acc as usize])
+}
+
+/// Parses the encoded id, with or without a trailing check symbol.
 impl std::str::FromStr for UntypedId {
     type Err = Error;
     fn from_str(src: &str) -> Result<Self, Self::Err> {
         let mut bytes = [0u8; 16];
-        if src.len() != ENCODED_BARE_ID_LEN {
-            return Err(IdParseError::Unparseable.into());
-        }
+        let (encoded, check) = match src.len() {
+            ENCODED_BARE_ID_LEN => (src.as_bytes(), None),
+            CHECKED_BARE_ID_LEN => {
+                let (encoded, check) = src.as_bytes().split_at(ENCODED_BARE_ID_LEN);
+                (encoded, Some(check[0]))
+            }
+            _ => return Err(IdParseError::Unparseable.into()),
+        };
         BASE32_DNSSEC
-            .decode_mut(src.as_bytes(), &mut bytes)
+            .decode_mut(encoded, &mut bytes)
             .map_err(IdParseError::from)?;
 
+        if let Some(check) = check {
+            if check_symbol(encoded) != Some(check) {
+                return Err(IdParseError::InvalidCheckSymbol.into());
+            }
+        }
+
         Ok(Self::from_bytes(&bytes[..]))
     }
 }
 
+/// The alternate form (`{:#}`) appends a check symbol, so that mistyped ids
+/// can be detected.
 impl fmt::Display for UntypedId {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut buf = [0u8; ENCODED_BARE_ID_LEN];
-        BASE32_DNSSEC.encode_mut(&self.to_bytes(), &mut buf);
-
-        write!(fmt, "{}", String::from_utf8_lossy(&buf[..]))?;
+        let mut buf = [0u8; CHECKED_BARE_ID_LEN];
+        BASE32_DNSSEC.encode_mut(&self.to_bytes(), &mut buf[..ENCODED_BARE_ID_LEN]);
+
+        let len = if fmt.alternate() {
+            buf[ENCODED_BARE_ID_LEN] =
+                check_symbol(&buf[..ENCODED_BARE_ID_LEN]).expect("encoded symbols");
+            CHECKED_BARE_ID_LEN
+        } else {
+            ENCODED_BARE_ID_LEN
+        };
+
+        write!(fmt, "{}", String::from_utf8_lossy(&buf[..len]))?;
         Ok(())
     }
 }
 
+/// Reads the compact 16 byte form of an id, as used by non human-readable
+/// formats.
+pub(crate) struct IdBytesVisitor;
+
 impl Serialize for UntypedId {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
     }
 }
 
@@ -127,7 +242,40 @@ impl<'de> Deserialize<'de> for UntypedId {
             }
         }
 
-        deserializer.deserialize_str(IdStrVisitor)
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdStrVisitor)
+        } else {
+            deserializer.deserialize_bytes(IdBytesVisitor)
+        }
+    }
+}
+
+impl<'vi> de::Visitor<'vi> for IdBytesVisitor {
+    type Value = UntypedId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "16 id bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<UntypedId, E> {
+        if value.len() != 16 {
+            return Err(E::invalid_length(value.len(), &self));
+        }
+        Ok(UntypedId::from_bytes(value))
+    }
+
+    // Some formats represent bytes as a sequence.
+    fn visit_seq<A: de::SeqAccess<'vi>>(self, mut seq: A) -> Result<UntypedId, A::Error> {
+        let mut bytes = [0u8; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(17, &self));
+        }
+        Ok(UntypedId::from_bytes(&bytes))
     }
 }
 
@@ -164,6 +312,149 @@ mod test {
         assert_eq!(id, id2);
     }
 
+    #[test]
+    fn at_should_use_given_time_and_random() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_nanos(1_577_836_800_123_456_789);
+        let id = UntypedId::at(t, 42).expect("in range");
+
+        assert_eq!(id.timestamp(), t);
+        assert_eq!(id.random(), 42);
+    }
+
+    #[test]
+    fn at_should_reject_times_before_1970() {
+        let t = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(UntypedId::at(t, 0), Err(TimeOutOfRange(t)));
+    }
+
+    #[test]
+    fn at_should_reject_times_too_late_for_the_stamp() {
+        let last = SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::MAX);
+        assert!(UntypedId::at(last, 0).is_ok());
+        let t = last + Duration::from_nanos(1);
+        assert_eq!(UntypedId::at(t, 0), Err(TimeOutOfRange(t)));
+    }
+
+    #[test]
+    fn round_trips_via_checked_form() {
+        let id = IdGen::new().untyped();
+
+        let s = format!("{:#}", id);
+        assert_eq!(s.len(), CHECKED_BARE_ID_LEN);
+        assert_eq!(s.parse::<UntypedId>().expect("parse id"), id);
+    }
+
+    #[test]
+    fn checked_form_should_extend_plain_form() {
+        let id = IdGen::new().untyped();
+
+        assert!(format!("{:#}", id).starts_with(&id.to_string()));
+    }
+
+    #[test]
+    fn should_detect_substituted_symbol() {
+        let id = IdGen::new().untyped();
+        let s = format!("{:#}", id);
+
+        for i in 0..ENCODED_BARE_ID_LEN {
+            for &c in ENCODED_SYMBOLS.iter() {
+                let mut typo = s.clone().into_bytes();
+                if typo[i] == c {
+                    continue;
+                }
+                typo[i] = c;
+                let typo = String::from_utf8(typo).expect("utf8");
+                assert!(
+                    typo.parse::<UntypedId>().is_err(),
+                    "Parsing {:?} (from {:?}) should fail",
+                    typo,
+                    s
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn should_detect_transposed_symbols() {
+        let id = IdGen::new().untyped();
+        let s = format!("{:#}", id);
+
+        for i in 0..ENCODED_BARE_ID_LEN - 1 {
+            let mut typo = s.clone().into_bytes();
+            if typo[i] == typo[i + 1] {
+                continue;
+            }
+            typo.swap(i, i + 1);
+            let typo = String::from_utf8(typo).expect("utf8");
+            assert!(
+                typo.parse::<UntypedId>().is_err(),
+                "Parsing {:?} (from {:?}) should fail",
+                typo,
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn should_report_mismatched_check_symbol() {
+        let s = "0000000000001q5nnvfqq7krfo";
+        let check = check_symbol(s.as_bytes()).expect("check symbol");
+        let wrong = CHECK_SYMBOLS
+            .iter()
+            .find(|&&c| c != check)
+            .expect("other symbol");
+        let typo = format!("{}{}", s, *wrong as char);
+
+        let err = typo.parse::<UntypedId>().expect_err("parse should fail");
+        assert!(
+            matches!(
+                err.downcast_ref::<IdParseError>(),
+                Some(IdParseError::InvalidCheckSymbol)
+            ),
+            "Error: {:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn round_trips_via_bincode() {
+        let id = IdGen::new().untyped();
+
+        let bytes = bincode::serialize(&id).expect("bincode::serialize");
+        // Length prefix, then the id itself.
+        assert_eq!(bytes.len(), 8 + 16);
+        let id2: UntypedId = bincode::deserialize(&bytes).expect("bincode::deserialize");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn round_trips_via_cbor() {
+        let id = IdGen::new().untyped();
+
+        let bytes = serde_cbor::to_vec(&id).expect("serde_cbor::to_vec");
+        assert_eq!(bytes.len(), 1 + 16);
+        let id2: UntypedId = serde_cbor::from_slice(&bytes).expect("serde_cbor::from_slice");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn round_trips_via_msgpack() {
+        let id = IdGen::new().untyped();
+
+        let bytes = rmp_serde::to_vec(&id).expect("rmp_serde::to_vec");
+        assert_eq!(bytes.len(), 2 + 16);
+        let id2: UntypedId = rmp_serde::from_slice(&bytes).expect("rmp_serde::from_slice");
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn should_reject_short_binary_form() {
+        let short = serde_cbor::Value::Bytes(vec![0u8; 15]);
+        let bytes = serde_cbor::to_vec(&short).expect("serde_cbor::to_vec");
+
+        assert!(serde_cbor::from_slice::<UntypedId>(&bytes).is_err());
+    }
+
     #[test]
     fn serializes_to_string_like() {
         let id = UntypedId::hashed(&"boo");
@@ -194,6 +485,102 @@ mod test {
         assert!(id < id2 || id > id2);
     }
 
+    #[test]
+    fn monotonic_should_strictly_increase_under_rapid_generation() {
+        let idgen = IdGen::monotonic();
+        let ids = (0..10_000).map(|_| idgen.untyped()).collect::<Vec<_>>();
+
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn monotonic_should_be_shared_between_clones() {
+        let idgen = IdGen::monotonic();
+        let other = idgen.clone();
+        for _ in 0..1000 {
+            let a = idgen.untyped();
+            let b = other.untyped();
+            assert!(a < b, "{:?} < {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn monotonic_should_not_go_backwards_when_clock_regresses() {
+        let mut last = UntypedId::default();
+        let first = next_monotonic(&mut last, 2_000, 0xffff);
+        let regressed = next_monotonic(&mut last, 1_000, 0x0001);
+        let same_tick = next_monotonic(&mut last, 2_000, 0x0002);
+        let recovered = next_monotonic(&mut last, 3_000, 0x0003);
+
+        assert!(first < regressed, "{:?} < {:?}", first, regressed);
+        assert!(regressed < same_tick, "{:?} < {:?}", regressed, same_tick);
+        assert!(same_tick < recovered, "{:?} < {:?}", same_tick, recovered);
+        assert_eq!(regressed.stamp, first.stamp);
+        assert_eq!(recovered.stamp, 3_000);
+    }
+
+    #[test]
+    fn monotonic_should_advance_stamp_when_random_overflows() {
+        let mut last = UntypedId::default();
+        let first = next_monotonic(&mut last, 2_000, u64::MAX);
+        let next = next_monotonic(&mut last, 1_000, 0x1234);
+
+        assert!(first < next, "{:?} < {:?}", first, next);
+        assert_eq!(next.stamp, first.stamp + 1);
+    }
+
+    #[test]
+    fn hashed_with_default_key_should_match_hashed() {
+        assert_eq!(
+            UntypedId::hashed_with(&IdKey::default(), "Hi!"),
+            UntypedId::hashed("Hi!")
+        );
+    }
+
+    #[test]
+    fn hashed_with_secret_should_differ_from_unkeyed() {
+        let key = IdKey::from_secret("sekrit");
+
+        assert_ne!(
+            UntypedId::hashed_with(&key, "Hi!"),
+            UntypedId::hashed("Hi!")
+        );
+        assert_eq!(
+            UntypedId::hashed_with(&key, "Hi!"),
+            UntypedId::hashed_with(&IdKey::from_secret("sekrit"), "Hi!")
+        );
+    }
+
+    #[test]
+    fn derive_should_keep_parent_timestamp() {
+        let parent = IdGen::new().untyped();
+        let child = parent.derive(&"child");
+
+        assert_eq!(child.timestamp(), parent.timestamp());
+        assert_ne!(child, parent);
+    }
+
+    #[test]
+    fn derive_should_be_stable() {
+        let parent = UntypedId::hashed(&"parent");
+
+        assert_eq!(parent.derive(&1u64), parent.derive(&1u64));
+    }
+
+    #[test]
+    fn derive_should_distinguish_discriminators_and_parents() {
+        let idgen = IdGen::new();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..64 {
+            let parent = idgen.untyped();
+            for n in 0u64..64 {
+                assert!(seen.insert(parent.derive(&n)), "Duplicate child id");
+            }
+        }
+    }
+
     #[test]
     fn should_parse_expected_len() {
         let s = "0000000000001q5nnvfqq7krfo";
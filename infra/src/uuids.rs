@@ -0,0 +1,174 @@
+//! Conversions between our ids and `uuid::Uuid`.
+//!
+//! The uuid holds the 64-bit nanosecond timestamp then the 64-bit random
+//! portion, both big-endian, so uuids compare in the same order as the ids.
+//! Every one of the 128 bits carries data, so the conversion is lossless;
+//! but that leaves no room for version or variant bits, and the results are
+//! not UUIDs of any version. Don't expect other tools to read a time from
+//! them.
+
+use std::convert::TryInto;
+
+use uuid::Uuid;
+
+use crate::ids::Id;
+use crate::untyped_ids::UntypedId;
+
+impl From<UntypedId> for Uuid {
+    fn from(src: UntypedId) -> Self {
+        let bytes = src.to_bytes();
+        Uuid::from_bytes(bytes[..].try_into().expect("id bytes"))
+    }
+}
+
+impl From<Uuid> for UntypedId {
+    fn from(src: Uuid) -> Self {
+        UntypedId::from_bytes(src.as_bytes())
+    }
+}
+
+impl<T> From<Id<T>> for Uuid {
+    fn from(src: Id<T>) -> Self {
+        src.untyped().into()
+    }
+}
+
+impl<T> From<Uuid> for Id<T> {
+    fn from(src: Uuid) -> Self {
+        Id::from_untyped(src.into())
+    }
+}
+
+#[cfg(feature = "postgres-uuid")]
+mod sql {
+    use std::error::Error;
+
+    use postgres::to_sql_checked;
+    use postgres::types::{FromSql, IsNull, ToSql, Type, UUID};
+
+    use crate::ids::Id;
+    use crate::untyped_ids::UntypedId;
+
+    impl ToSql for UntypedId {
+        fn to_sql(
+            &self,
+            _: &Type,
+            out: &mut Vec<u8>,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            out.extend(self.to_bytes());
+            Ok(IsNull::No)
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == UUID
+        }
+
+        to_sql_checked!();
+    }
+
+    impl FromSql for UntypedId {
+        fn from_sql(_: &Type, raw: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            if raw.len() != 16 {
+                return Err(format!("Invalid uuid length: {}", raw.len()).into());
+            }
+            Ok(UntypedId::from_bytes(raw))
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == UUID
+        }
+    }
+
+    impl<T> ToSql for Id<T> {
+        fn to_sql(
+            &self,
+            ty: &Type,
+            out: &mut Vec<u8>,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            self.untyped().to_sql(ty, out)
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == UUID
+        }
+
+        to_sql_checked!();
+    }
+
+    impl<T> FromSql for Id<T> {
+        fn from_sql(ty: &Type, raw: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            Ok(Id::from_untyped(UntypedId::from_sql(ty, raw)?))
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            *ty == UUID
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ids::{Entity, IdGen};
+
+    #[derive(Debug)]
+    struct Canary;
+
+    impl Entity for Canary {
+        const PREFIX: &'static str = "canary";
+    }
+
+    #[test]
+    fn round_trips_via_uuid() {
+        let id = IdGen::new().generate::<Canary>();
+
+        let uuid = Uuid::from(id);
+        let id2 = Id::<Canary>::from(uuid);
+        assert_eq!(id, id2);
+    }
+
+    #[test]
+    fn untyped_round_trips_via_uuid() {
+        let id = IdGen::new().untyped();
+
+        let uuid = Uuid::from(id);
+        assert_eq!(UntypedId::from(uuid), id);
+    }
+
+    #[test]
+    fn uuids_should_preserve_id_ordering() {
+        let idgen = IdGen::deterministic(0);
+        let a = idgen.generate::<Canary>();
+        let b = idgen.generate::<Canary>();
+        assert!(a < b);
+
+        assert!(Uuid::from(a) < Uuid::from(b));
+    }
+
+    #[test]
+    fn uuid_should_lead_with_timestamp() {
+        let id = Id::<Canary>::hashed("Hi!");
+        let uuid = Uuid::from(id);
+
+        assert_eq!(uuid.as_bytes()[..8], id.untyped().to_bytes()[..8]);
+    }
+
+    #[cfg(feature = "postgres-uuid")]
+    #[test]
+    fn round_trips_via_postgres_uuid() -> Result<(), anyhow::Error> {
+        use anyhow::Context;
+        use postgres::{Connection, TlsMode};
+
+        let url = std::env::var("POSTGRES_URL").with_context(|| "$POSTGRES_URL")?;
+        let conn = Connection::connect(&*url, TlsMode::None)?;
+        let id = IdGen::new().generate::<Canary>();
+
+        let rows = conn.query("SELECT $1::uuid, $1::uuid::text", &[&id])?;
+        let id2: Id<Canary> = rows.get(0).get(0);
+        let text: String = rows.get(0).get(1);
+
+        assert_eq!(id, id2);
+        assert_eq!(text, Uuid::from(id).to_hyphenated().to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use crate::ids::IdGen;
+use crate::untyped_ids::UntypedId;
+
+/// Identifies a worker process in the liveness registry. Each process
+/// registers a separate entry per entity prefix that it consumes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerLease {
+    pub worker_id: UntypedId,
+    pub host: String,
+    pub pid: u32,
+    pub started_at: SystemTime,
+}
+
+/// A registry entry for a worker that has sent a heartbeat recently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStatus {
+    pub worker_id: UntypedId,
+    pub host: String,
+    pub pid: u32,
+    pub prefix: String,
+    pub started_at: SystemTime,
+    pub heartbeat_at: SystemTime,
+    pub last_claimed: Option<String>,
+    pub last_claimed_at: Option<SystemTime>,
+}
+
+impl WorkerLease {
+    pub fn for_current_process(idgen: &IdGen) -> Self {
+        WorkerLease {
+            worker_id: idgen.untyped(),
+            host: hostname(),
+            pid: process::id(),
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+pub(crate) fn to_epoch_secs(t: SystemTime) -> f64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+pub(crate) fn from_epoch_secs(secs: f64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs.max(0.0))
+}
+
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -5,6 +5,6 @@ set -euxo pipefail
 
 RB=./target/debug/rb
 
-DRINK=${1-"drink.0eghfh5gl9ivhq5nnvfqq7krfo"}
+DRINK=${1-$(${RB} dev-config.toml show-menu | awk -F: 'NR == 1 { print $1 }')}
 order_id=$(${RB} dev-config.toml order ${DRINK})
 ${RB} dev-config.toml order-status ${order_id}